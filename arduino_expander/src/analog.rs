@@ -0,0 +1,82 @@
+use arduino_hal::pac::ADC;
+use gpio_actions::{AnalogConfig, AnalogReference, ErrorCode};
+
+// We program the ADC registers ourselves instead of using arduino_hal::Adc, because that requires turning a pin
+// into an analog pin for good, and our pins have to stay usable as digital pins.
+const ADCSRA_ADEN: u8 = 1 << 7;
+const ADCSRA_ADSC: u8 = 1 << 6;
+// 16MHz / 128 = 125kHz, the ADC needs between 50kHz and 200kHz for full resolution
+const ADCSRA_ADPS_128: u8 = 0b111;
+
+fn reference_bits(reference: AnalogReference) -> u8 {
+    match reference {
+        AnalogReference::External => 0b00 << 6,
+        AnalogReference::AVcc => 0b01 << 6,
+        AnalogReference::Internal1V1 => 0b11 << 6,
+    }
+}
+
+pub struct AnalogInput {
+    adc: ADC,
+    config: AnalogConfig,
+    settled_reference: Option<AnalogReference>,
+}
+
+impl AnalogInput {
+    pub fn new(adc: ADC) -> Self {
+        adc.adcsra.write(|w| unsafe { w.bits(ADCSRA_ADEN | ADCSRA_ADPS_128) });
+        Self {
+            adc,
+            config: AnalogConfig::default(),
+            settled_reference: None,
+        }
+    }
+
+    /// Use `config` for every reading from now on. There has to be at least one sample
+    pub fn configure(&mut self, config: AnalogConfig) -> Result<(), ErrorCode> {
+        if config.samples == 0 {
+            return Err(ErrorCode::InvalidArgument);
+        }
+        self.config = config;
+        Ok(())
+    }
+
+    pub fn config(&self) -> AnalogConfig {
+        self.config
+    }
+
+    /// Measure `channel`, averaging as many conversions as configured
+    pub fn read(&mut self, channel: u8) -> u16 {
+        self.select(channel, self.config.reference);
+        let samples = self.config.samples;
+        let mut sum = 0_u32;
+        for _ in 0..samples {
+            sum += self.convert() as u32;
+        }
+        (sum / samples as u32) as u16
+    }
+
+    /// Measure `channel` against `reference` with a single conversion, no matter how the ADC is configured
+    pub fn read_once(&mut self, channel: u8, reference: AnalogReference) -> u16 {
+        self.select(channel, reference);
+        self.convert()
+    }
+
+    fn select(&mut self, channel: u8, reference: AnalogReference) {
+        self.adc
+            .admux
+            .write(|w| unsafe { w.bits(reference_bits(reference) | channel) });
+
+        // The first conversion after switching the reference is inaccurate, so we throw it away
+        if self.settled_reference != Some(reference) {
+            self.convert();
+            self.settled_reference = Some(reference);
+        }
+    }
+
+    fn convert(&mut self) -> u16 {
+        self.adc.adcsra.modify(|r, w| unsafe { w.bits(r.bits() | ADCSRA_ADSC) });
+        while self.adc.adcsra.read().bits() & ADCSRA_ADSC != 0 {}
+        self.adc.adc.read().bits()
+    }
+}
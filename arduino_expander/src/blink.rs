@@ -0,0 +1,127 @@
+use crate::{pins::MAX_PINS, ports};
+use avr_device::interrupt::{self, Mutex};
+use core::cell::RefCell;
+use gpio_actions::{Blink, ErrorCode, PinState, Port};
+use heapless::Vec;
+
+/// Tracks where a pin is in its blink pattern. It's advanced by the clock's timer interrupt, so the pattern keeps
+/// going while actions like Pulse keep the main loop busy or a response waits for the serial link
+struct Blinker {
+    port: Port,
+    bit: u8,
+    /// Open-drain pins are released instead of driven high, which changes their direction
+    open_drain: bool,
+    high_ms: u32,
+    period_ms: u32,
+    elapsed_ms: u32,
+    /// Periods until the pin stays low, or `None` to blink until told otherwise
+    periods_left: Option<u16>,
+    level: PinState,
+}
+
+impl Blinker {
+    /// Advance by `ms` milliseconds. Returns the level the pin changes to, if it does
+    fn advance(&mut self, ms: u32) -> Option<PinState> {
+        self.elapsed_ms += ms;
+        while self.elapsed_ms >= self.period_ms {
+            self.elapsed_ms -= self.period_ms;
+            if let Some(periods_left) = self.periods_left.as_mut() {
+                *periods_left = periods_left.saturating_sub(1);
+            }
+        }
+        let level = if self.periods_left != Some(0) && self.elapsed_ms < self.high_ms {
+            PinState::High
+        } else {
+            PinState::Low
+        };
+        if level == self.level {
+            return None;
+        }
+        self.level = level;
+        Some(level)
+    }
+
+    fn write(&self, level: PinState) {
+        let mask = 1 << self.bit;
+        match (self.open_drain, level) {
+            (false, PinState::High) => ports::write(self.port, mask, mask),
+            (false, PinState::Low) => ports::write(self.port, mask, 0),
+            (true, PinState::High) => ports::write_direction(self.port, mask, 0),
+            (true, PinState::Low) => ports::write_direction(self.port, mask, mask),
+        }
+    }
+}
+
+static BLINKERS: Mutex<RefCell<Vec<Blinker, MAX_PINS>>> = Mutex::new(RefCell::new(Vec::new()));
+
+/// How long the pin stays high in every period of `config`, rounded down to whole milliseconds
+fn high_ms(config: Blink) -> u32 {
+    config.period_ms as u32 * config.duty as u32 / 100
+}
+
+/// Whether `config` describes a blink pattern that can be generated
+pub fn check(config: Blink) -> Result<(), ErrorCode> {
+    if config.period_ms == 0 || config.duty > 100 {
+        return Err(ErrorCode::InvalidArgument);
+    }
+    // Short periods can't resolve every duty cycle. Rather than never blinking, or staying high, such patterns fail
+    let high_ms = high_ms(config);
+    if config.duty > 0 && high_ms == 0 || config.duty < 100 && high_ms == config.period_ms as u32 {
+        return Err(ErrorCode::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// Start blinking the pin at `bit` of `port` as `config` says, which has to pass [`check`]. The pin has to be driven
+/// low already. Open-drain pins are released instead of driven high
+pub fn start(port: Port, bit: u8, open_drain: bool, config: Blink) {
+    stop(port, bit);
+    let mut blinker = Blinker {
+        port,
+        bit,
+        open_drain,
+        high_ms: high_ms(config),
+        period_ms: config.period_ms as u32,
+        elapsed_ms: 0,
+        periods_left: config.count,
+        level: PinState::Low,
+    };
+    if let Some(level) = blinker.advance(0) {
+        blinker.write(level);
+    }
+    // Every pin fits, as there's only one blinker per pin
+    interrupt::free(|cs| BLINKERS.borrow(cs).borrow_mut().push(blinker).unwrap_or_default());
+}
+
+/// Stop blinking the pin at `bit` of `port`, leaving it at its current level. Returns whether it was blinking at all,
+/// even if all of its periods were over already. Its registers were changed behind the pin's back in that case
+pub fn stop(port: Port, bit: u8) -> bool {
+    interrupt::free(|cs| {
+        let mut blinkers = BLINKERS.borrow(cs).borrow_mut();
+        match blinkers
+            .iter()
+            .position(|blinker| (blinker.port, blinker.bit) == (port, bit))
+        {
+            Some(index) => {
+                blinkers.swap_remove(index);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Advance every blinking pin by `ms` milliseconds. Called from the clock's timer interrupt
+pub fn tick(ms: u32) {
+    interrupt::free(|cs| {
+        for blinker in BLINKERS.borrow(cs).borrow_mut().iter_mut() {
+            // Pins that are done stay in the list until they're stopped, so the pin learns about its new level
+            if blinker.periods_left == Some(0) {
+                continue;
+            }
+            if let Some(level) = blinker.advance(ms) {
+                blinker.write(level);
+            }
+        }
+    });
+}
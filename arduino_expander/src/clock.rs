@@ -0,0 +1,92 @@
+use crate::{blink, failsafe};
+use arduino_hal::pac::{tc0, TC0};
+use avr_device::interrupt::{self, Mutex};
+use core::cell::Cell;
+
+// Timer 0 runs in fast PWM mode with a prescaler of 64 for pins 5 and 6 anyway, see the pwm module. Like the Arduino
+// core, we keep the time by counting its overflows, so its prescaler must never change.
+const TIMSK0_TOIE0: u8 = 1 << 0;
+const TIFR0_TOV0: u8 = 1 << 0;
+const MICROS_PER_TICK: u32 = 64 / 16;
+// One overflow takes 256 ticks, which is 1.024ms. The fraction is counted in steps of 8µs so it fits into a u8
+const MILLIS_PER_OVERFLOW: u32 = 1;
+const FRACT_PER_OVERFLOW: u8 = 24 / 8;
+const FRACT_PER_MILLI: u8 = 1000 / 8;
+
+#[derive(Clone, Copy)]
+struct Time {
+    overflows: u32,
+    millis: u32,
+    fract: u8,
+}
+
+static TIME: Mutex<Cell<Time>> = Mutex::new(Cell::new(Time {
+    overflows: 0,
+    millis: 0,
+    fract: 0,
+}));
+
+fn tc0() -> &'static tc0::RegisterBlock {
+    unsafe { &*TC0::ptr() }
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn TIMER0_OVF() {
+    let elapsed_ms = interrupt::free(|cs| {
+        let cell = TIME.borrow(cs);
+        let mut time = cell.get();
+        let millis = time.millis;
+        time.overflows = time.overflows.wrapping_add(1);
+        time.millis = time.millis.wrapping_add(MILLIS_PER_OVERFLOW);
+        time.fract += FRACT_PER_OVERFLOW;
+        if time.fract >= FRACT_PER_MILLI {
+            time.fract -= FRACT_PER_MILLI;
+            time.millis = time.millis.wrapping_add(1);
+        }
+        cell.set(time);
+        time.millis.wrapping_sub(millis)
+    });
+    // Blinking from here keeps it going no matter how busy the main loop is
+    blink::tick(elapsed_ms);
+}
+
+/// Start counting time. Must be called after `pwm::init`, which starts timer 0
+pub fn init() {
+    tc0().timsk0.write(|w| unsafe { w.bits(TIMSK0_TOIE0) });
+}
+
+/// Milliseconds since `init`. Wraps around after ~50 days
+pub fn millis() -> u32 {
+    interrupt::free(|cs| TIME.borrow(cs).get().millis)
+}
+
+/// Microseconds since `init`, with a resolution of 4µs. Wraps around after ~71 minutes
+pub fn micros() -> u32 {
+    interrupt::free(|cs| {
+        let mut overflows = TIME.borrow(cs).get().overflows;
+        let ticks = tc0().tcnt0.read().bits();
+        // The timer may have overflowed after interrupts were disabled, in which case the handler didn't count it yet
+        if tc0().tifr0.read().bits() & TIFR0_TOV0 != 0 && ticks < u8::MAX {
+            overflows = overflows.wrapping_add(1);
+        }
+        ((overflows << 8) | ticks as u32).wrapping_mul(MICROS_PER_TICK)
+    })
+}
+
+/// Longest time that may be spent with interrupts disabled. At 10 bits per byte and 57600 baud, a byte arrives every
+/// 174µs. The USART holds two received bytes while the third is shifted in, so a byte that's already waiting when
+/// interrupts go off leaves about 2 * 174µs = 348µs until it overruns. This leaves some margin, and the clock's
+/// overflows every 1024µs are safe as well
+pub const MAX_CRITICAL_MICROS: u32 = 300;
+
+/// Wait until `us` microseconds have passed since `start`, which was taken from `micros`. Returns false if the wait was
+/// cut short, because the failsafe expired in the meantime
+pub fn wait_until(start: u32, us: u32) -> bool {
+    let mut watch = failsafe::Watch::default();
+    while micros().wrapping_sub(start) < us {
+        if watch.expired() {
+            return false;
+        }
+    }
+    true
+}
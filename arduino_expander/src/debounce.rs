@@ -0,0 +1,61 @@
+use gpio_actions::{Debounce, PinState};
+
+/// Filters raw readings of a pin, so its level only changes once it has settled
+pub struct Debouncer {
+    config: Debounce,
+    stable: PinState,
+    candidate_since: Option<u32>,
+    candidate_samples: u8,
+}
+
+impl Debouncer {
+    pub fn new(config: Debounce, level: PinState) -> Self {
+        Self {
+            config,
+            stable: level,
+            candidate_since: None,
+            candidate_samples: 0,
+        }
+    }
+
+    pub fn config(&self) -> Debounce {
+        self.config
+    }
+
+    /// The last level that counted as settled
+    pub fn stable(&self) -> PinState {
+        self.stable
+    }
+
+    /// Forget about any bounces and start over at `level`, for example because the pin changed its mode
+    pub fn reset(&mut self, level: PinState) {
+        *self = Self::new(self.config, level);
+    }
+
+    /// Feed a raw reading taken at `now` milliseconds. Returns the new level if it just settled
+    pub fn update(&mut self, level: PinState, now: u32) -> Option<PinState> {
+        if level == self.stable {
+            self.candidate_since = None;
+            self.candidate_samples = 0;
+            return None;
+        }
+
+        let settled = match self.config {
+            Debounce::Off => true,
+            Debounce::Millis(millis) => {
+                let since = *self.candidate_since.get_or_insert(now);
+                now.wrapping_sub(since) >= millis as u32
+            }
+            Debounce::Samples(samples) => {
+                self.candidate_samples = self.candidate_samples.saturating_add(1);
+                self.candidate_samples >= samples
+            }
+        };
+        if settled {
+            self.reset(level);
+            Some(level)
+        } else {
+            None
+        }
+    }
+}
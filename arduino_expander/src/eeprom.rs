@@ -0,0 +1,76 @@
+use arduino_hal::pac::EEPROM;
+use avr_device::interrupt;
+use gpio_actions::crc16;
+
+const EECR_EERE: u8 = 1 << 0;
+const EECR_EEPE: u8 = 1 << 1;
+const EECR_EEMPE: u8 = 1 << 2;
+
+// Where each record starts. Every record is followed by the CRC-16 of its contents, so a record that was never
+// written or got corrupted can be told apart from a valid one
+/// See [`crate::power_on::PowerOn`]
+pub const POWER_ON_ADDRESS: u16 = 0;
+/// See [`crate::labels::LabelTable`]
+pub const LABELS_ADDRESS: u16 = 32;
+/// See [`crate::identity::DeviceIdentity`]
+pub const DEVICE_ID_ADDRESS: u16 = 136;
+/// See [`crate::identity::DeviceIdentity`]
+pub const DEVICE_NAME_ADDRESS: u16 = 160;
+
+pub struct Eeprom {
+    eeprom: EEPROM,
+}
+
+impl Eeprom {
+    pub fn new(eeprom: EEPROM) -> Self {
+        Self { eeprom }
+    }
+
+    /// Fill `buffer` with the bytes starting at `address`
+    pub fn read(&mut self, address: u16, buffer: &mut [u8]) {
+        for (address, byte) in (address..).zip(buffer) {
+            self.wait();
+            self.eeprom.eear.write(|w| unsafe { w.bits(address) });
+            self.eeprom.eecr.write(|w| unsafe { w.bits(EECR_EERE) });
+            *byte = self.eeprom.eedr.read().bits();
+        }
+    }
+
+    /// Write `data` starting at `address`. Every byte takes about 3.4ms, so bytes that wouldn't change are skipped,
+    /// which also spares the EEPROM's limited write cycles
+    pub fn write(&mut self, address: u16, data: &[u8]) {
+        for (address, &byte) in (address..).zip(data) {
+            let mut current = [0];
+            self.read(address, &mut current);
+            if current[0] == byte {
+                continue;
+            }
+            self.eeprom.eedr.write(|w| unsafe { w.bits(byte) });
+            // The write only starts if EEPE is set within four cycles of EEMPE, so nothing may interrupt them
+            interrupt::free(|_| {
+                self.eeprom.eecr.write(|w| unsafe { w.bits(EECR_EEMPE) });
+                self.eeprom.eecr.write(|w| unsafe { w.bits(EECR_EEMPE | EECR_EEPE) });
+            });
+        }
+    }
+
+    /// Fill `buffer` with the record at `address`. Returns false if its checksum doesn't match, in which case the
+    /// contents of `buffer` are meaningless
+    pub fn read_record(&mut self, address: u16, buffer: &mut [u8]) -> bool {
+        let mut checksum = [0; 2];
+        self.read(address, buffer);
+        self.read(address + buffer.len() as u16, &mut checksum);
+        u16::from_le_bytes(checksum) == crc16(buffer)
+    }
+
+    /// Write `data` as the record at `address`, together with its checksum
+    pub fn write_record(&mut self, address: u16, data: &[u8]) {
+        self.write(address, data);
+        self.write(address + data.len() as u16, &crc16(data).to_le_bytes());
+    }
+
+    /// Wait until the last write is done, nothing can be read or written before that
+    fn wait(&self) {
+        while self.eeprom.eecr.read().bits() & EECR_EEPE != 0 {}
+    }
+}
@@ -0,0 +1,351 @@
+use crate::{
+    clock,
+    ports::{self, index},
+};
+use arduino_hal::pac::EXINT;
+use avr_device::interrupt::{self, Mutex};
+use core::cell::{Cell, RefCell};
+use gpio_actions::{CaptureChunk, Edge, PinState, Port, CAPTURE_CHUNK_LEN, MAX_CAPTURE_EDGES};
+use heapless::Vec;
+
+/// Level changes the pin change interrupts latched on one port
+#[derive(Clone, Copy)]
+struct Edges {
+    rising: u8,
+    falling: u8,
+    level: u8,
+}
+
+impl Edges {
+    const fn new() -> Self {
+        Self {
+            rising: 0,
+            falling: 0,
+            level: 0,
+        }
+    }
+}
+
+/// Edges counted on the pins of one port
+#[derive(Clone, Copy)]
+struct Counters {
+    rising: u8,
+    falling: u8,
+    counts: [u32; 8],
+    overflowed: u8,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            rising: 0,
+            falling: 0,
+            counts: [0; 8],
+            overflowed: 0,
+        }
+    }
+
+    fn count(&mut self, rose: u8, fell: u8) {
+        let counted = (rose & self.rising) | (fell & self.falling);
+        for bit in 0..8 {
+            if counted & 1 << bit != 0 {
+                let (count, overflowed) = self.counts[bit].overflowing_add(1);
+                self.counts[bit] = count;
+                if overflowed {
+                    self.overflowed |= 1 << bit;
+                }
+            }
+        }
+    }
+}
+
+/// The time and new level of every change of a single pin, until the buffer is full. Nothing is recorded while the
+/// limit is zero
+struct Capture {
+    port: Port,
+    bit: u8,
+    limit: u16,
+    start: u32,
+    micros: Vec<u32, { MAX_CAPTURE_EDGES as usize }>,
+    levels: u64,
+}
+
+impl Capture {
+    const STOPPED: Self = Self {
+        port: Port::B,
+        bit: 0,
+        limit: 0,
+        start: 0,
+        micros: Vec::new(),
+        levels: 0,
+    };
+
+    /// This capture, unless it's stopped
+    fn active(&self) -> Option<&Self> {
+        if self.limit > 0 {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn record(&mut self, port: Port, changed: u8, level: u8) {
+        let mask = 1 << self.bit;
+        if port != self.port || changed & mask == 0 || self.micros.len() >= self.limit as usize {
+            return;
+        }
+        if level & mask != 0 {
+            self.levels |= 1 << self.micros.len();
+        }
+        self.micros
+            .push(clock::micros().wrapping_sub(self.start))
+            .unwrap_or_default();
+    }
+
+    /// As many of the first `total` edges as fit into a [`gpio_actions::Response::Capture`], starting at `offset`
+    fn chunk(&self, offset: u16, total: u16) -> CaptureChunk {
+        let mut chunk = CaptureChunk {
+            offset,
+            total,
+            micros: [0; CAPTURE_CHUNK_LEN],
+            levels: 0,
+        };
+        let offset = offset as usize;
+        let len = (total as usize)
+            .min(self.micros.len())
+            .saturating_sub(offset)
+            .min(CAPTURE_CHUNK_LEN);
+        for (index, &micros) in self.micros[offset..offset + len].iter().enumerate() {
+            chunk.micros[index] = micros;
+            chunk.levels |= ((self.levels >> (offset + index)) as u8 & 1) << index;
+        }
+        chunk
+    }
+}
+
+/// Level changes on all ports since the main loop last looked
+pub struct LatchedEdges([Edges; 3]);
+
+impl LatchedEdges {
+    /// The levels the pin at `bit` of `port` changed to, in the order they happened, as far as `edge` selects them
+    pub fn transitions(&self, port: Port, bit: u8, edge: Edge) -> [Option<PinState>; 2] {
+        let edges = self.0[index(port)];
+        let mask = 1 << bit;
+        let rise = (edges.rising & mask != 0 && edge.matches(PinState::High)).then(|| PinState::High);
+        let fall = (edges.falling & mask != 0 && edge.matches(PinState::Low)).then(|| PinState::Low);
+        // If both edges happened, the one leading to the current level came last
+        if edges.level & mask != 0 {
+            [fall, rise]
+        } else {
+            [rise, fall]
+        }
+    }
+}
+
+// One entry per port, in the order of the PCINT vectors
+static EDGES: [Mutex<Cell<Edges>>; 3] = [
+    Mutex::new(Cell::new(Edges::new())),
+    Mutex::new(Cell::new(Edges::new())),
+    Mutex::new(Cell::new(Edges::new())),
+];
+
+static COUNTERS: [Mutex<Cell<Counters>>; 3] = [
+    Mutex::new(Cell::new(Counters::new())),
+    Mutex::new(Cell::new(Counters::new())),
+    Mutex::new(Cell::new(Counters::new())),
+];
+
+// Only one pin is captured at a time, the buffer takes up a good part of the RAM already. It's only ever changed in
+// place, as moving a new capture in would need as much room on the stack again
+static CAPTURE: Mutex<RefCell<Capture>> = Mutex::new(RefCell::new(Capture::STOPPED));
+
+// PCMSK0 to 2 enable pin change interrupts for the pins of PORTB to PORTD respectively
+fn watched_pins(port: Port) -> u8 {
+    // The EXINT peripheral is owned by PinChangeInterrupts, but the interrupt handlers only ever read it
+    let exint = unsafe { &*EXINT::ptr() };
+    match port {
+        Port::B => exint.pcmsk0.read().bits(),
+        Port::C => exint.pcmsk1.read().bits(),
+        Port::D => exint.pcmsk2.read().bits(),
+    }
+}
+
+fn latch(port: Port) {
+    let level = ports::read(port);
+    let watched = watched_pins(port);
+    interrupt::free(|cs| {
+        let cell = EDGES[index(port)].borrow(cs);
+        let mut edges = cell.get();
+        let changed = (edges.level ^ level) & watched;
+        edges.rising |= changed & level;
+        edges.falling |= changed & !level;
+        edges.level = level;
+        cell.set(edges);
+
+        let cell = COUNTERS[index(port)].borrow(cs);
+        let mut counters = cell.get();
+        counters.count(changed & level, changed & !level);
+        cell.set(counters);
+
+        CAPTURE.borrow(cs).borrow_mut().record(port, changed, level);
+    });
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn PCINT0() {
+    latch(Port::B);
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn PCINT1() {
+    latch(Port::C);
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn PCINT2() {
+    latch(Port::D);
+}
+
+/// Watches pins for level changes using the pin change interrupts, which every pin of the ATmega328P has
+pub struct PinChangeInterrupts {
+    exint: EXINT,
+}
+
+impl PinChangeInterrupts {
+    pub fn new(exint: EXINT) -> Self {
+        // Each port only causes interrupts for the pins enabled in its mask, which are none yet
+        const PCICR_PCIE_ALL: u8 = 0b111;
+        exint.pcicr.write(|w| unsafe { w.bits(PCICR_PCIE_ALL) });
+        Self { exint }
+    }
+
+    /// Start or stop latching level changes of the pin at `bit` of `port`
+    pub fn watch(&mut self, port: Port, bit: u8, enable: bool) {
+        let update = |bits: u8| if enable { bits | 1 << bit } else { bits & !(1 << bit) };
+        interrupt::free(|cs| {
+            // Otherwise, a change from before the pin was watched could be reported
+            let cell = EDGES[index(port)].borrow(cs);
+            let mut edges = cell.get();
+            edges.level = (edges.level & !(1 << bit)) | (ports::read(port) & 1 << bit);
+            edges.rising &= !(1 << bit);
+            edges.falling &= !(1 << bit);
+            cell.set(edges);
+
+            match port {
+                Port::B => self.exint.pcmsk0.modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+                Port::C => self.exint.pcmsk1.modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+                Port::D => self.exint.pcmsk2.modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+            }
+        });
+    }
+
+    /// Start counting edges of the pin at `bit` of `port` from zero, or stop if `edge` is `None`.
+    /// The pin has to be watched for this to work
+    pub fn count(&mut self, port: Port, bit: u8, edge: Option<Edge>) {
+        let mask = 1 << bit;
+        let select = |selected: bool, bits: u8| if selected { bits | mask } else { bits & !mask };
+        interrupt::free(|cs| {
+            let cell = COUNTERS[index(port)].borrow(cs);
+            let mut counters = cell.get();
+            counters.rising = select(
+                matches!(edge, Some(edge) if edge.matches(PinState::High)),
+                counters.rising,
+            );
+            counters.falling = select(
+                matches!(edge, Some(edge) if edge.matches(PinState::Low)),
+                counters.falling,
+            );
+            counters.counts[bit as usize] = 0;
+            counters.overflowed &= !mask;
+            cell.set(counters);
+        });
+    }
+
+    /// Whether edges of the pin at `bit` of `port` are being counted
+    pub fn is_counting(&self, port: Port, bit: u8) -> bool {
+        interrupt::free(|cs| {
+            let counters = COUNTERS[index(port)].borrow(cs).get();
+            (counters.rising | counters.falling) & 1 << bit != 0
+        })
+    }
+
+    /// The number of edges counted on the pin at `bit` of `port`, and whether the count overflowed since it started.
+    /// If `reset` is set, counting starts over at zero
+    pub fn read_count(&mut self, port: Port, bit: u8, reset: bool) -> (u32, bool) {
+        let mask = 1 << bit;
+        interrupt::free(|cs| {
+            let cell = COUNTERS[index(port)].borrow(cs);
+            let mut counters = cell.get();
+            let count = (counters.counts[bit as usize], counters.overflowed & mask != 0);
+            if reset {
+                counters.counts[bit as usize] = 0;
+                counters.overflowed &= !mask;
+                cell.set(counters);
+            }
+            count
+        })
+    }
+
+    /// Start recording up to `limit` edges of the pin at `bit` of `port`, or stop if `limit` is zero. Whatever was
+    /// recorded before is dropped, even if it was another pin. The pin has to be watched for this to work
+    pub fn capture(&mut self, port: Port, bit: u8, limit: u16) {
+        let start = clock::micros();
+        interrupt::free(|cs| {
+            let mut capture = CAPTURE.borrow(cs).borrow_mut();
+            capture.port = port;
+            capture.bit = bit;
+            capture.limit = limit;
+            capture.start = start;
+            capture.micros.clear();
+            capture.levels = 0;
+        });
+    }
+
+    /// The pin whose edges are being recorded, if any
+    pub fn capturing(&self) -> Option<(Port, u8)> {
+        interrupt::free(|cs| {
+            let capture = CAPTURE.borrow(cs).borrow();
+            capture.active().map(|capture| (capture.port, capture.bit))
+        })
+    }
+
+    /// The number of edges recorded so far, if the pin at `bit` of `port` is being captured
+    pub fn capture_len(&self, port: Port, bit: u8) -> Option<u16> {
+        interrupt::free(|cs| {
+            let capture = CAPTURE.borrow(cs).borrow();
+            capture
+                .active()
+                .filter(|capture| (capture.port, capture.bit) == (port, bit))
+                .map(|capture| capture.micros.len() as u16)
+        })
+    }
+
+    /// A copy of the recorded edges from `offset` on, as many as fit into one chunk. Only the first `total` edges are
+    /// included, so all chunks agree on it even if more edges come in while they're sent. The whole capture is too
+    /// large to copy onto the stack at once
+    pub fn read_capture(&self, port: Port, bit: u8, offset: u16, total: u16) -> Option<CaptureChunk> {
+        interrupt::free(|cs| {
+            let capture = CAPTURE.borrow(cs).borrow();
+            capture
+                .active()
+                .filter(|capture| (capture.port, capture.bit) == (port, bit))
+                .map(|capture| capture.chunk(offset, total))
+        })
+    }
+
+    /// The level changes latched since the last call
+    pub fn take_edges(&mut self) -> LatchedEdges {
+        interrupt::free(|cs| {
+            LatchedEdges(Port::ALL.map(|port| {
+                let cell = EDGES[index(port)].borrow(cs);
+                let edges = cell.get();
+                cell.set(Edges {
+                    rising: 0,
+                    falling: 0,
+                    level: edges.level,
+                });
+                edges
+            }))
+        })
+    }
+}
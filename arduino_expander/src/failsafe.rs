@@ -0,0 +1,102 @@
+use crate::{clock, receiver};
+use avr_device::interrupt::{self, Mutex};
+use core::cell::Cell;
+
+/// Notices when the host stops sending frames, so the outputs can be put into a safe state. Disabled by default
+#[derive(Clone, Copy)]
+struct Failsafe {
+    timeout_ms: u16,
+    last_frame: u32,
+    tripped_at: Option<u32>,
+}
+
+impl Failsafe {
+    /// Whether nothing at all arrived for the whole timeout at `now`, not even the start of a frame
+    fn expired(&self, now: u32, last_byte: u32) -> bool {
+        let timeout_ms = self.timeout_ms as u32;
+        self.timeout_ms != 0
+            && now.wrapping_sub(self.last_frame) >= timeout_ms
+            && now.wrapping_sub(last_byte) >= timeout_ms
+    }
+}
+
+// Kept in a static, so actions that keep the main loop busy can give up once the host is gone
+static FAILSAFE: Mutex<Cell<Failsafe>> = Mutex::new(Cell::new(Failsafe {
+    timeout_ms: 0,
+    last_frame: 0,
+    tripped_at: None,
+}));
+
+fn update<R>(change: impl FnOnce(&mut Failsafe) -> R) -> R {
+    interrupt::free(|cs| {
+        let cell = FAILSAFE.borrow(cs);
+        let mut failsafe = cell.get();
+        let result = change(&mut failsafe);
+        cell.set(failsafe);
+        result
+    })
+}
+
+/// Trip once no frame arrived for `timeout_ms` since `now`, or never if it's zero
+pub fn set_timeout(timeout_ms: u16, now: u32) {
+    update(|failsafe| {
+        failsafe.timeout_ms = timeout_ms;
+        failsafe.last_frame = now;
+    });
+}
+
+/// A frame arrived at `now` milliseconds. Returns how long ago the failsafe tripped, if it did since the last frame
+pub fn feed(now: u32) -> Option<u32> {
+    update(|failsafe| {
+        failsafe.last_frame = now;
+        failsafe
+            .tripped_at
+            .take()
+            .map(|tripped_at| now.wrapping_sub(tripped_at))
+    })
+}
+
+/// An action that kept the main loop busy is done at `now` milliseconds. Frames that arrived in the meantime weren't
+/// read yet, so the time it took doesn't count against the host, unless the host really went silent
+pub fn resume(now: u32) {
+    let last_byte = receiver::last_byte();
+    update(|failsafe| {
+        if !failsafe.expired(now, last_byte) {
+            failsafe.last_frame = now;
+        }
+    });
+}
+
+/// Whether the host just missed its deadline at `now` milliseconds. Only true once until the next frame arrives
+pub fn check(now: u32) -> bool {
+    update(|failsafe| {
+        let expired = now.wrapping_sub(failsafe.last_frame) >= failsafe.timeout_ms as u32;
+        if failsafe.timeout_ms == 0 || failsafe.tripped_at.is_some() || !expired {
+            return false;
+        }
+        failsafe.tripped_at = Some(now);
+        true
+    })
+}
+
+/// Whether the host hasn't sent anything for the whole timeout at `now` milliseconds, counting bytes that weren't read
+/// yet. Actions that keep the main loop busy give up once this is true, so [`check`] can trip
+pub fn expired(now: u32) -> bool {
+    let last_byte = receiver::last_byte();
+    interrupt::free(|cs| FAILSAFE.borrow(cs).get().expired(now, last_byte))
+}
+
+/// Lets a loop that keeps the main loop busy notice that the host is gone
+#[derive(Default)]
+pub struct Watch {
+    calls: u8,
+}
+
+impl Watch {
+    /// Whether the failsafe expired, see [`expired`]. Looking that up takes a few µs, which would throw off the timing
+    /// of tight loops, so it's only done on every 256th call
+    pub fn expired(&mut self) -> bool {
+        self.calls = self.calls.wrapping_add(1);
+        self.calls == 0 && expired(clock::millis())
+    }
+}
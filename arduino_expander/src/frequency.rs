@@ -0,0 +1,62 @@
+use crate::{clock, failsafe};
+use arduino_hal::pac::TC1;
+use gpio_actions::{ErrorCode, Port};
+
+/// The T1 pin, which can clock timer 1 directly. That's the only pin whose frequency can be measured
+pub const COUNTER_PIN: (Port, u8) = (Port::D, 5);
+
+// Clock select: external clock on T1, rising edge
+const TCCR1B_CS_EXTERNAL_RISING: u8 = 0b111;
+const TIFR1_TOV1: u8 = 1 << 0;
+
+/// Count the rising edges on [`COUNTER_PIN`] for `gate_ms` milliseconds and return their frequency in Hz. Gives up
+/// with [`ErrorCode::Aborted`] if the failsafe expires in the meantime.
+///
+/// Timer 1 is borrowed from the pwm module for this, so PWM on its pins pauses in the meantime.
+pub fn measure(gate_ms: u16) -> Result<u32, ErrorCode> {
+    // The pwm module owns timer 1, but it's not used anywhere else while we're busy here
+    let tc1 = unsafe { &*TC1::ptr() };
+    let tccr1a = tc1.tccr1a.read().bits();
+    let tccr1b = tc1.tccr1b.read().bits();
+
+    // Normal mode with the compare outputs disconnected, so the counter goes all the way to 0xFFFF
+    tc1.tccr1a.write(|w| unsafe { w.bits(0) });
+    tc1.tcnt1.write(|w| unsafe { w.bits(0) });
+    tc1.tifr1.write(|w| unsafe { w.bits(TIFR1_TOV1) });
+    let start = clock::micros();
+    tc1.tccr1b.write(|w| unsafe { w.bits(TCCR1B_CS_EXTERNAL_RISING) });
+
+    // The counter only has 16 bits, so we count its overflows on the side. Even at the highest frequency the timer can
+    // count, an overflow only happens every 10ms, so there's no way to miss one
+    let mut overflows = 0_u32;
+    let gate_us = gate_ms as u32 * 1000;
+    let mut watch = failsafe::Watch::default();
+    let mut aborted = false;
+    while clock::micros().wrapping_sub(start) < gate_us {
+        if watch.expired() {
+            aborted = true;
+            break;
+        }
+        if tc1.tifr1.read().bits() & TIFR1_TOV1 != 0 {
+            tc1.tifr1.write(|w| unsafe { w.bits(TIFR1_TOV1) });
+            overflows += 1;
+        }
+    }
+    tc1.tccr1b.write(|w| unsafe { w.bits(0) });
+    if tc1.tifr1.read().bits() & TIFR1_TOV1 != 0 {
+        tc1.tifr1.write(|w| unsafe { w.bits(TIFR1_TOV1) });
+        overflows += 1;
+    }
+    let edges = (overflows << 16) | tc1.tcnt1.read().bits() as u32;
+
+    tc1.tcnt1.write(|w| unsafe { w.bits(0) });
+    tc1.tccr1a.write(|w| unsafe { w.bits(tccr1a) });
+    tc1.tccr1b.write(|w| unsafe { w.bits(tccr1b) });
+    if aborted {
+        return Err(ErrorCode::Aborted);
+    }
+
+    // Multiplying first could overflow
+    let gate_ms = gate_ms as u32;
+    Ok(edges / gate_ms * 1000 + edges % gate_ms * 1000 / gate_ms)
+}
@@ -0,0 +1,88 @@
+use crate::{
+    analog::AnalogInput,
+    eeprom::{Eeprom, DEVICE_ID_ADDRESS, DEVICE_NAME_ADDRESS},
+};
+use gpio_actions::{AnalogReference, DeviceId, DeviceName, Identity, MAX_DEVICE_NAME_LEN};
+
+// The internal temperature sensor, which can only be measured against the internal reference. Its lowest bits are
+// mostly noise, which is all we want from it
+const TEMPERATURE_CHANNEL: u8 = 8;
+// The analog pins. Until they're set up they float, so their lowest bits are noise too, unless something is connected
+const FLOATING_CHANNELS: [u8; 6] = [0, 1, 2, 3, 4, 5];
+const SAMPLES_PER_BYTE: u8 = 32;
+
+/// The ID and name of the board, kept in EEPROM
+pub struct DeviceIdentity {
+    identity: Identity,
+}
+
+impl DeviceIdentity {
+    /// The identity stored in `eeprom`. A board that doesn't have an ID yet gets a new one, which is stored right away.
+    /// Has to be called before the pins are set up, see [`random_id`]
+    pub fn load(eeprom: &mut Eeprom, analog_input: &mut AnalogInput) -> Self {
+        let mut id = [0; 16];
+        if !eeprom.read_record(DEVICE_ID_ADDRESS, &mut id) {
+            id = random_id(analog_input);
+            eeprom.write_record(DEVICE_ID_ADDRESS, &id);
+        }
+        let mut name = [0; MAX_DEVICE_NAME_LEN];
+        let name = if eeprom.read_record(DEVICE_NAME_ADDRESS, &mut name) {
+            core::str::from_utf8(&name)
+                .ok()
+                .and_then(|name| DeviceName::new(name.trim_end_matches('\0')))
+        } else {
+            None
+        };
+        let identity = Identity {
+            id: DeviceId(id),
+            name: name.unwrap_or_default(),
+        };
+        Self { identity }
+    }
+
+    pub fn identity(&self) -> Identity {
+        self.identity
+    }
+
+    /// Replace the ID for good, even a factory reset keeps it
+    pub fn set_id(&mut self, id: DeviceId, eeprom: &mut Eeprom) {
+        self.identity.id = id;
+        eeprom.write_record(DEVICE_ID_ADDRESS, &id.0);
+    }
+
+    pub fn set_name(&mut self, name: DeviceName, eeprom: &mut Eeprom) {
+        // Whatever as_str cuts off would be lost after a reset anyway
+        let name = DeviceName::new(name.as_str()).unwrap_or_default();
+        self.identity.name = name;
+        store_name(name, eeprom);
+    }
+
+    /// Forget the name once the board is reset. The ID stays the same forever
+    pub fn reset(&self, eeprom: &mut Eeprom) {
+        store_name(DeviceName::default(), eeprom);
+    }
+}
+
+fn store_name(name: DeviceName, eeprom: &mut Eeprom) {
+    let mut bytes = [0; MAX_DEVICE_NAME_LEN];
+    bytes[..name.as_str().len()].copy_from_slice(name.as_str().as_bytes());
+    eeprom.write_record(DEVICE_NAME_ADDRESS, &bytes);
+}
+
+/// A version 4 UUID from the noise in the lowest bits of the ADC. Not good enough for cryptography, but boards won't
+/// end up with the same one. Interrupts aren't enabled yet, so timing adds nothing but steps of the same length
+fn random_id(analog_input: &mut AnalogInput) -> [u8; 16] {
+    let mut id = [0; 16];
+    let mut floating_channels = FLOATING_CHANNELS.iter().cycle();
+    for byte in id.iter_mut() {
+        for _ in 0..SAMPLES_PER_BYTE {
+            let temperature = analog_input.read_once(TEMPERATURE_CHANNEL, AnalogReference::Internal1V1);
+            let channel = floating_channels.next().copied().unwrap_or_default();
+            let floating = analog_input.read_once(channel, AnalogReference::AVcc);
+            *byte = byte.rotate_left(3) ^ temperature as u8 ^ (floating as u8).rotate_left(1);
+        }
+    }
+    id[6] = id[6] & 0x0f | 0x40;
+    id[8] = id[8] & 0x3f | 0x80;
+    id
+}
@@ -0,0 +1,45 @@
+use crate::{
+    eeprom::{Eeprom, LABELS_ADDRESS},
+    ports,
+};
+use gpio_actions::{PinLabel, Port};
+
+/// The label of every pin, kept in EEPROM so they survive a reset
+#[derive(Default)]
+pub struct LabelTable {
+    /// One entry for every pin of each port, see [`ports::pin_index`]
+    labels: [Option<PinLabel>; 24],
+}
+
+impl LabelTable {
+    /// The table stored in `eeprom`, if one was ever stored and isn't corrupted
+    pub fn load(eeprom: &mut Eeprom) -> Option<Self> {
+        let mut bytes = [0; 24 * 4];
+        if !eeprom.read_record(LABELS_ADDRESS, &mut bytes) {
+            return None;
+        }
+        let mut table = Self::default();
+        for (label, bytes) in table.labels.iter_mut().zip(bytes.chunks_exact(4)) {
+            // Zero marks pins without a label
+            let code = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            *label = char::from_u32(code).filter(|&label| label != '\0');
+        }
+        Some(table)
+    }
+
+    pub fn get(&self, port: Port, bit: u8) -> Option<PinLabel> {
+        self.labels[ports::pin_index(port, bit)]
+    }
+
+    pub fn set(&mut self, port: Port, bit: u8, label: Option<PinLabel>) {
+        self.labels[ports::pin_index(port, bit)] = label;
+    }
+
+    pub fn store(&self, eeprom: &mut Eeprom) {
+        let mut bytes = [0; 24 * 4];
+        for (bytes, label) in bytes.chunks_exact_mut(4).zip(self.labels) {
+            bytes.copy_from_slice(&label.map_or(0, u32::from).to_le_bytes());
+        }
+        eeprom.write_record(LABELS_ADDRESS, &bytes);
+    }
+}
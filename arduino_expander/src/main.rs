@@ -1,7 +1,25 @@
 #![no_std]
 #![no_main]
+#![feature(abi_avr_interrupt)]
 
+mod analog;
+mod blink;
+mod clock;
+mod debounce;
+mod eeprom;
+mod events;
+mod failsafe;
+mod frequency;
+mod identity;
+mod labels;
+mod pattern;
 mod pins;
+mod ports;
+mod power_on;
+mod pwm;
+mod receiver;
+mod stream;
+use analog::AnalogInput;
 use arduino_hal::{
     hal::port::{PD0, PD1},
     pac::USART0,
@@ -11,78 +29,426 @@ use arduino_hal::{
     },
     Usart,
 };
-use gpio_actions::{Action, Response, TryFromIter, MAX_ACTION_WIRE_SIZE};
-use heapless::Vec;
+use eeprom::Eeprom;
+use events::PinChangeInterrupts;
+use gpio_actions::{
+    to_frame, Action, Board, Capabilities, DeviceInfo, Envelope, ErrorCode, FrameReader, PinLabel, PinMask, Playback,
+    Port, PulseTrain, Response, TransactionId, Version, BAUD_RATE, MAX_ACTION_FRAME_SIZE, MAX_RESPONSE_FRAME_SIZE,
+    NO_TRANSACTION, PROTOCOL_VERSION,
+};
+use identity::DeviceIdentity;
 use pins::PinDispatcher;
+use power_on::PowerOn;
 
 use panic_halt as _;
 
 type BoardSerial = Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>;
 
-struct UnoSerial<'a>(&'a mut BoardSerial);
-
-impl<'a> Iterator for UnoSerial<'a> {
-    type Item = u8;
-    fn next(&mut self) -> Option<u8> {
-        Some(self.0.read_byte())
-    }
-}
+const FIRMWARE_VERSION: Version = Version::parse(env!("CARGO_PKG_VERSION"));
+const BOARD: Board = Board::ArduinoUno;
+const CAPABILITIES: Capabilities = Capabilities::ANALOG_INPUT
+    .union(Capabilities::PWM)
+    .union(Capabilities::ATOMIC_PORTS)
+    .union(Capabilities::EVENTS)
+    .union(Capabilities::DEBOUNCE)
+    .union(Capabilities::PULSE)
+    .union(Capabilities::BLINK)
+    .union(Capabilities::MEASURE_PULSE)
+    .union(Capabilities::EDGE_COUNTERS)
+    .union(Capabilities::FREQUENCY)
+    .union(Capabilities::CAPTURE)
+    .union(Capabilities::STREAM)
+    .union(Capabilities::PATTERN)
+    .union(Capabilities::FAILSAFE)
+    .union(Capabilities::POWER_ON)
+    .union(Capabilities::LABELS)
+    .union(Capabilities::IDENTITY)
+    .union(Capabilities::PIN_INFO)
+    .union(Capabilities::QUERY)
+    .union(Capabilities::SET_MODE)
+    .union(Capabilities::MANY_PINS)
+    .union(Capabilities::SET_ID)
+    .union(Capabilities::LIST_PORTS);
 
-fn send_response(serial: &mut BoardSerial, response: Response) {
+fn send_response(serial: &mut BoardSerial, id: TransactionId, response: Response) {
+    let envelope = Envelope { id, payload: response };
+    let mut buffer = [0_u8; MAX_RESPONSE_FRAME_SIZE];
     // We have to use unwrap_or_default() instead of unwrap() here, otherwise the size of the .elf baloons by ~10K.
     // I think this is because the panic!() inside unwrap() has to format a lot of stuff.
-    let serialized: Vec<u8, 32> = postcard::to_vec(&response).unwrap_or_default();
-    for byte in serialized {
+    let frame = to_frame(&envelope, &mut buffer).unwrap_or_default();
+    for &byte in frame {
         serial.write_byte(byte);
     }
 }
 
+/// Answer an action on `pin_label` with `response`, or with the error that prevented it
+fn pin_response(pin_label: PinLabel, response: Result<Response, ErrorCode>) -> Response {
+    response.unwrap_or_else(|error| Response::Err(error, Some(pin_label)))
+}
+
 #[arduino_hal::entry]
 fn main() -> ! {
     let dp = arduino_hal::Peripherals::take().unwrap();
     let pins = arduino_hal::pins!(dp);
 
-    let mut serial = arduino_hal::default_serial!(dp, pins, 57600);
+    let mut serial = arduino_hal::default_serial!(dp, pins, BAUD_RATE);
+    receiver::init();
+
+    let mut analog_input = AnalogInput::new(dp.ADC);
+    pwm::init(dp.TC0, dp.TC1, dp.TC2);
+    clock::init();
+    let mut pin_change_interrupts = PinChangeInterrupts::new(dp.EXINT);
+
+    let mut eeprom = Eeprom::new(dp.EEPROM);
+    // While the analog pins still float, as a new ID is made from their noise
+    let mut identity = DeviceIdentity::load(&mut eeprom, &mut analog_input);
 
     let mut pin_dispatcher = PinDispatcher::new();
+    // These labels are only the defaults, the ones stored in EEPROM replace them below
     add_pin!(pin_dispatcher, pins.d13, '1');
     add_pin!(pin_dispatcher, pins.d2, '2');
-    add_pin!(pin_dispatcher, pins.d3, '3');
+    add_pin!(pin_dispatcher, pins.d3, '3', pwm Timer2B);
     add_pin!(pin_dispatcher, pins.d4, '4');
-    add_pin!(pin_dispatcher, pins.d5, '5');
-    add_pin!(pin_dispatcher, pins.d6, '6');
+    add_pin!(pin_dispatcher, pins.d5, '5', pwm Timer0B);
+    add_pin!(pin_dispatcher, pins.d6, '6', pwm Timer0A);
     add_pin!(pin_dispatcher, pins.d7, '7');
     add_pin!(pin_dispatcher, pins.d8, '8');
-    add_pin!(pin_dispatcher, pins.d9, '9');
-    add_pin!(pin_dispatcher, pins.d10, 'a');
-    add_pin!(pin_dispatcher, pins.d11, 'b');
+    add_pin!(pin_dispatcher, pins.d9, '9', pwm Timer1A);
+    add_pin!(pin_dispatcher, pins.d10, 'a', pwm Timer1B);
+    add_pin!(pin_dispatcher, pins.d11, 'b', pwm Timer2A);
     add_pin!(pin_dispatcher, pins.d12, 'c');
 
-    add_pin!(pin_dispatcher, pins.a0, 'A');
-    add_pin!(pin_dispatcher, pins.a1, 'B');
-    add_pin!(pin_dispatcher, pins.a2, 'C');
-    add_pin!(pin_dispatcher, pins.a3, 'D');
-    add_pin!(pin_dispatcher, pins.a4, 'E');
-    add_pin!(pin_dispatcher, pins.a5, 'F');
+    add_pin!(pin_dispatcher, pins.a0, 'A', analog 0);
+    add_pin!(pin_dispatcher, pins.a1, 'B', analog 1);
+    add_pin!(pin_dispatcher, pins.a2, 'C', analog 2);
+    add_pin!(pin_dispatcher, pins.a3, 'D', analog 3);
+    add_pin!(pin_dispatcher, pins.a4, 'E', analog 4);
+    add_pin!(pin_dispatcher, pins.a5, 'F', analog 5);
 
+    pin_dispatcher.load_labels(&mut eeprom);
+    let mut power_on = PowerOn::load(&mut eeprom);
+    pin_dispatcher.apply_power_on(&power_on);
+
+    // Safety: Nothing the interrupt handlers touch is accessed outside of a critical section
+    unsafe { avr_device::interrupt::enable() };
+
+    let mut frame_reader = FrameReader::<MAX_ACTION_FRAME_SIZE>::new();
+    // PlaybackDone is sent with the ID of the Play action that started the playback
+    let mut playback_id = NO_TRANSACTION;
     loop {
-        match Action::try_from_iter::<MAX_ACTION_WIRE_SIZE>(&mut UnoSerial(&mut serial)) {
-            postcard::Result::Ok(action) => match action {
+        pin_dispatcher.poll(&mut pin_change_interrupts, clock::millis(), |pin_label, state| {
+            send_response(&mut serial, NO_TRANSACTION, Response::Event(pin_label, state))
+        });
+        if let Some(frame) = stream::take_frame() {
+            send_response(&mut serial, NO_TRANSACTION, Response::Samples(frame));
+        }
+        if let Some(played) = pattern::finished() {
+            send_response(&mut serial, playback_id, Response::PlaybackDone(played));
+        }
+        if failsafe::check(clock::millis()) {
+            // Nothing may keep driving the outputs on behalf of a host that's gone
+            if let Some(played) = pattern::stop() {
+                send_response(&mut serial, playback_id, Response::PlaybackDone(played));
+            }
+            pin_dispatcher.failsafe();
+        }
+
+        // We can't block here, otherwise events would only be reported when the next action comes in
+        let byte = match receiver::read() {
+            Some(byte) => byte,
+            None => continue,
+        };
+        let frame = frame_reader.push::<Envelope<Action>>(byte);
+        let dispatched = matches!(frame, Some(Ok(_)));
+        if dispatched {
+            if let Some(tripped_ms) = failsafe::feed(clock::millis()) {
+                send_response(&mut serial, NO_TRANSACTION, Response::FailsafeTripped(tripped_ms));
+            }
+        }
+        match frame {
+            None => (),
+            Some(Ok(Envelope { id, payload: action })) => match action {
                 Action::Output(pin_label, write_state) => {
-                    pin_dispatcher.output(pin_label, write_state);
-                    send_response(&mut serial, Response::Output(pin_label, write_state));
+                    let response = pin_dispatcher
+                        .output(pin_label, write_state)
+                        .map(|()| Response::Output(pin_label, write_state));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
                 }
                 Action::Input(pin_label) => {
-                    let read_state = pin_dispatcher.input(pin_label);
-                    send_response(&mut serial, Response::Input(pin_label, read_state));
+                    let response = pin_dispatcher
+                        .input(pin_label)
+                        .map(|read_state| Response::Input(pin_label, read_state));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
                 }
                 Action::List => {
-                    for (pin_label, pin) in &pin_dispatcher {
-                        send_response(&mut serial, Response::List(*pin_label, pin.name()));
+                    for (pin_label, pin) in pin_dispatcher.iter() {
+                        send_response(&mut serial, id, Response::List(pin_label, pin.name()));
+                    }
+                }
+                Action::Hello => {
+                    let device_info = DeviceInfo {
+                        protocol_version: PROTOCOL_VERSION,
+                        firmware_version: FIRMWARE_VERSION,
+                        board: BOARD,
+                        pin_count: pin_dispatcher.pin_count(),
+                        capabilities: CAPABILITIES,
+                    };
+                    send_response(&mut serial, id, Response::Hello(device_info));
+                }
+                Action::SetMode(pin_label, mode) => {
+                    let response = pin_dispatcher
+                        .set_mode(pin_label, mode)
+                        .map(|()| Response::Mode(pin_label, mode));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::AnalogRead(pin_label) => {
+                    let response = pin_dispatcher
+                        .analog_read(pin_label, &mut analog_input)
+                        .map(|value| Response::Analog(pin_label, value));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::ConfigureAnalog(config) => {
+                    let response = match analog_input.configure(config) {
+                        Ok(()) => Response::AnalogConfig(analog_input.config()),
+                        Err(error) => Response::Err(error, None),
+                    };
+                    send_response(&mut serial, id, response);
+                }
+                Action::Pwm(pin_label, duty) => {
+                    let response = pin_dispatcher
+                        .pwm(pin_label, duty)
+                        .map(|()| Response::Pwm(pin_label, duty));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::ConfigurePwm(pin_label, prescaler) => {
+                    let response = pin_dispatcher
+                        .configure_pwm(pin_label, prescaler)
+                        .map(|()| Response::PwmConfig(pin_label, prescaler));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::InputMany(mask) => {
+                    let (mask, levels) = pin_dispatcher.input_many(mask);
+                    send_response(&mut serial, id, Response::InputMany(mask, levels));
+                }
+                Action::OutputMany(mask, levels) => {
+                    let mask = pin_dispatcher.output_many(mask, levels);
+                    send_response(&mut serial, id, Response::OutputMany(mask, levels.intersection(mask)));
+                }
+                Action::Ports | Action::ListPorts => {
+                    let ports = Port::ALL.map(|port| (port, pin_dispatcher.port_pins(port)));
+                    let used = ports.iter().filter(|(_, pins)| *pins != PinMask::NONE);
+                    if action == Action::ListPorts {
+                        send_response(&mut serial, id, Response::PortCount(used.clone().count() as u8));
+                    }
+                    for &(port, pins) in used {
+                        send_response(&mut serial, id, Response::Port(port, pins));
+                    }
+                }
+                Action::OutputAtomic(mask, levels) => {
+                    let response = match pin_dispatcher.output_atomic(mask, levels) {
+                        Ok(mask) => Response::OutputAtomic(mask, levels.intersection(mask)),
+                        Err(error) => Response::Err(error, None),
+                    };
+                    send_response(&mut serial, id, response);
+                }
+                Action::Subscribe(pin_label, edge) => {
+                    let response = pin_dispatcher
+                        .subscribe(pin_label, Some(edge), &mut pin_change_interrupts)
+                        .map(|()| Response::Subscribed(pin_label, edge));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::Unsubscribe(pin_label) => {
+                    let response = pin_dispatcher
+                        .subscribe(pin_label, None, &mut pin_change_interrupts)
+                        .map(|()| Response::Unsubscribed(pin_label));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::Debounce(pin_label, config) => {
+                    let response = pin_dispatcher
+                        .debounce(pin_label, config)
+                        .map(|()| Response::Debounce(pin_label, config));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::Pulse(pin_label, state, width_us) => {
+                    let train = PulseTrain {
+                        state,
+                        width_us,
+                        count: 1,
+                        period_us: width_us,
+                    };
+                    let response = pin_dispatcher
+                        .pulse_train(pin_label, train)
+                        .map(|()| Response::Pulse(pin_label, state, width_us));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::PulseTrain(pin_label, train) => {
+                    let response = pin_dispatcher
+                        .pulse_train(pin_label, train)
+                        .map(|()| Response::PulseTrain(pin_label, train));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::Blink(pin_label, config) => {
+                    let response = pin_dispatcher
+                        .blink(pin_label, config)
+                        .map(|()| Response::Blink(pin_label, config));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::MeasurePulse(pin_label, state, timeout_us) => {
+                    let response = pin_dispatcher
+                        .measure_pulse(pin_label, state, timeout_us)
+                        .map(|width_us| Response::PulseWidth(pin_label, state, width_us));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::CountEdges(pin_label, edge) => {
+                    let response = pin_dispatcher
+                        .count_edges(pin_label, edge, &mut pin_change_interrupts)
+                        .map(|()| Response::CountingEdges(pin_label, edge));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::ReadCount(pin_label, reset) => {
+                    let response = pin_dispatcher
+                        .read_count(pin_label, reset, &mut pin_change_interrupts)
+                        .map(|(count, overflowed)| Response::Count(pin_label, count, overflowed));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::MeasureFrequency(pin_label, gate_ms) => {
+                    let response = pin_dispatcher
+                        .measure_frequency(pin_label, gate_ms)
+                        .map(|frequency| Response::Frequency(pin_label, frequency));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::Capture(pin_label, limit) => {
+                    let response = pin_dispatcher
+                        .capture(pin_label, limit, &mut pin_change_interrupts)
+                        .map(|()| Response::Capturing(pin_label, limit));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::ReadCapture(pin_label) => {
+                    match pin_dispatcher.read_capture(pin_label, &pin_change_interrupts) {
+                        Ok(chunks) => {
+                            for chunk in chunks {
+                                send_response(&mut serial, id, Response::Capture(pin_label, chunk));
+                            }
+                        }
+                        Err(error) => send_response(&mut serial, id, Response::Err(error, Some(pin_label))),
+                    }
+                }
+                Action::Stream(mask, period_us) => {
+                    // Samples taken so far belong to the previous stream, so they have to go out first
+                    for frame in stream::stop().into_iter().flatten() {
+                        send_response(&mut serial, NO_TRANSACTION, Response::Samples(frame));
+                    }
+                    let response = match pin_dispatcher.stream(mask, period_us) {
+                        Ok((streamed, period_us)) => Response::Streaming(streamed, period_us),
+                        Err(error) => Response::Err(error, None),
+                    };
+                    send_response(&mut serial, id, response);
+                }
+                Action::LoadPattern(mask, tick_us) => {
+                    let response = match pin_dispatcher.load_pattern(mask, tick_us) {
+                        Ok((driven, tick_us)) => Response::PatternLoaded(driven, tick_us),
+                        Err(error) => Response::Err(error, None),
+                    };
+                    send_response(&mut serial, id, response);
+                }
+                Action::AppendPattern(steps) => {
+                    let response = match pin_dispatcher.append_pattern(steps) {
+                        Ok(length) => Response::PatternLength(length),
+                        Err(error) => Response::Err(error, None),
+                    };
+                    send_response(&mut serial, id, response);
+                }
+                Action::Play(playback) => {
+                    if let Some(played) = pattern::stop() {
+                        send_response(&mut serial, playback_id, Response::PlaybackDone(played));
                     }
+                    let result = match playback {
+                        Playback::Stop => Ok(()),
+                        Playback::Once => pin_dispatcher.play(false),
+                        Playback::Loop => pin_dispatcher.play(true),
+                    };
+                    let response = match result {
+                        Ok(()) => {
+                            playback_id = id;
+                            Response::Playing(playback)
+                        }
+                        Err(error) => Response::Err(error, None),
+                    };
+                    send_response(&mut serial, id, response);
+                }
+                Action::Heartbeat(timeout_ms) => {
+                    failsafe::set_timeout(timeout_ms, clock::millis());
+                    send_response(&mut serial, id, Response::Heartbeat(timeout_ms));
+                }
+                Action::SafeState(pin_label, state) => {
+                    let response = pin_dispatcher
+                        .set_safe_state(pin_label, state)
+                        .map(|()| Response::SafeState(pin_label, state));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::PowerOn(pin_label, mode, state) => {
+                    let response = pin_dispatcher
+                        .set_power_on(pin_label, mode, state, &mut power_on, &mut eeprom)
+                        .map(|(mode, state)| Response::PowerOn(pin_label, mode, state));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::ReadPowerOn(pin_label) => {
+                    let response = pin_dispatcher
+                        .power_on(pin_label, &power_on)
+                        .map(|(mode, state)| Response::PowerOn(pin_label, mode, state));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::FactoryReset => {
+                    power_on.reset(&mut eeprom);
+                    pin_dispatcher.reset_labels(&mut eeprom);
+                    identity.reset(&mut eeprom);
+                    send_response(&mut serial, id, Response::FactoryReset);
+                }
+                Action::Label(pin_label, name) => {
+                    let response = pin_dispatcher
+                        .label(pin_label, name, &mut eeprom)
+                        .map(|()| Response::Labeled(pin_label, name));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::Unlabel(pin_label) => {
+                    let response = pin_dispatcher
+                        .unlabel(pin_label, &mut pin_change_interrupts, &mut eeprom)
+                        .map(|name| Response::Unlabeled(pin_label, name));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::Relabel(pin_label, new_label) => {
+                    let response = pin_dispatcher
+                        .relabel(pin_label, new_label, &mut eeprom)
+                        .map(|()| Response::Relabeled(pin_label, new_label));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::ListPins => {
+                    send_response(&mut serial, id, Response::PinCount(pin_dispatcher.pin_count()));
+                    for (index, (pin_label, pin)) in pin_dispatcher.iter().enumerate() {
+                        send_response(&mut serial, id, Response::Pin(pins::describe(index, pin_label, pin)));
+                    }
+                }
+                Action::Query(pin_label) => {
+                    let response = pin_dispatcher
+                        .query(pin_label)
+                        .map(|(mode, state)| Response::Query(pin_label, mode, state));
+                    send_response(&mut serial, id, pin_response(pin_label, response));
+                }
+                Action::Identify => send_response(&mut serial, id, Response::Identity(identity.identity())),
+                Action::SetName(name) => {
+                    identity.set_name(name, &mut eeprom);
+                    send_response(&mut serial, id, Response::Identity(identity.identity()));
+                }
+                Action::SetId(device_id) => {
+                    identity.set_id(device_id, &mut eeprom);
+                    send_response(&mut serial, id, Response::Identity(identity.identity()));
                 }
             },
-            postcard::Result::Err(_error) => (),
+            Some(Err(error)) => send_response(&mut serial, NO_TRANSACTION, Response::Err(error.into(), None)),
+        }
+        if dispatched {
+            // Actions like MeasurePulse keep us from reading frames for a while, which isn't the host's fault
+            failsafe::resume(clock::millis());
         }
     }
 }
@@ -0,0 +1,203 @@
+use crate::{ports, pwm::PwmChannel};
+use arduino_hal::pac::{tc1, TC1};
+use avr_device::interrupt::{self, Mutex};
+use core::cell::RefCell;
+use gpio_actions::{ErrorCode, Port, MAX_PATTERN_STEPS, MAX_PATTERN_TICK_US, MIN_PATTERN_TICK_US};
+use heapless::Vec;
+
+const TCCR1B_WGM_CTC: u8 = 1 << 3;
+const TIMSK1_OCIE1A: u8 = 1 << 1;
+// Prescalers of timer 1 and their clock select bits
+const PRESCALERS: [(u32, u8); 5] = [(1, 0b001), (8, 0b010), (64, 0b011), (256, 0b100), (1024, 0b101)];
+const TICKS_PER_MICRO: u32 = 16;
+
+/// One bit for every pin of each port, in the order of [`Port::ALL`]
+pub type Step = [u8; 3];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Playing,
+    /// Played to the end, but nobody was told yet
+    Finished,
+}
+
+/// The steps of the pattern and how far it's played
+struct Pattern {
+    mask: Step,
+    steps: Vec<Step, { MAX_PATTERN_STEPS as usize }>,
+    clock_select: u8,
+    compare: u16,
+    state: State,
+    looping: bool,
+    position: usize,
+    played: u32,
+    tccr1a: u8,
+    tccr1b: u8,
+}
+
+impl Pattern {
+    const fn new() -> Self {
+        Self {
+            mask: [0; 3],
+            steps: Vec::new(),
+            clock_select: 0,
+            compare: 0,
+            state: State::Idle,
+            looping: false,
+            position: 0,
+            played: 0,
+            tccr1a: 0,
+            tccr1b: 0,
+        }
+    }
+
+    /// Drive the pins to the next step, or stop once the last one has had its tick
+    fn advance(&mut self) {
+        if self.position == self.steps.len() {
+            if !self.looping {
+                self.finish();
+                return;
+            }
+            self.position = 0;
+        }
+        let step = self.steps[self.position];
+        for port in Port::ALL {
+            let mask = self.mask[ports::index(port)];
+            if mask != 0 {
+                ports::write(port, mask, step[ports::index(port)]);
+            }
+        }
+        self.position += 1;
+        self.played = self.played.wrapping_add(1);
+    }
+
+    fn finish(&mut self) {
+        tc1().timsk1.write(|w| unsafe { w.bits(0) });
+        tc1().tccr1a.write(|w| unsafe { w.bits(self.tccr1a) });
+        tc1().tccr1b.write(|w| unsafe { w.bits(self.tccr1b) });
+        self.state = State::Finished;
+    }
+}
+
+static PATTERN: Mutex<RefCell<Pattern>> = Mutex::new(RefCell::new(Pattern::new()));
+
+// The pwm module owns timer 1, but PWM on its pins is refused while playing, see `uses`
+fn tc1() -> &'static tc1::RegisterBlock {
+    unsafe { &*TC1::ptr() }
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn TIMER1_COMPA() {
+    interrupt::free(|cs| {
+        let mut pattern = PATTERN.borrow(cs).borrow_mut();
+        if pattern.state == State::Playing {
+            pattern.advance();
+        }
+    });
+}
+
+/// Whether `channel` is driven by the timer that times the steps
+pub fn uses(channel: PwmChannel) -> bool {
+    matches!(channel, PwmChannel::Timer1A | PwmChannel::Timer1B)
+}
+
+pub fn is_playing() -> bool {
+    interrupt::free(|cs| PATTERN.borrow(cs).borrow().state == State::Playing)
+}
+
+/// The pins the pattern drives
+pub fn mask() -> Step {
+    interrupt::free(|cs| PATTERN.borrow(cs).borrow().mask)
+}
+
+/// Empty the pattern and let it drive the pins in `mask` for `tick_us` each step. Returns the tick the timer actually
+/// runs at, which is rounded down to what its prescaler allows
+pub fn load(mask: Step, tick_us: u32) -> Result<u32, ErrorCode> {
+    if !(MIN_PATTERN_TICK_US..=MAX_PATTERN_TICK_US).contains(&tick_us) {
+        return Err(ErrorCode::InvalidArgument);
+    }
+    let ticks = tick_us * TICKS_PER_MICRO;
+    let (divisor, clock_select) = PRESCALERS
+        .into_iter()
+        .find(|&(divisor, _)| ticks / divisor <= 1 << 16)
+        .unwrap_or(PRESCALERS[PRESCALERS.len() - 1]);
+    let compare = ticks / divisor;
+
+    interrupt::free(|cs| {
+        let mut pattern = PATTERN.borrow(cs).borrow_mut();
+        if pattern.state == State::Playing {
+            return Err(ErrorCode::Busy);
+        }
+        pattern.mask = mask;
+        pattern.steps.clear();
+        pattern.clock_select = clock_select;
+        pattern.compare = (compare - 1) as u16;
+        Ok(compare * divisor / TICKS_PER_MICRO)
+    })
+}
+
+/// Add `steps` to the end of the pattern, either all of them or none. Returns the length of the pattern
+pub fn append(steps: &[Step]) -> Result<u16, ErrorCode> {
+    interrupt::free(|cs| {
+        let mut pattern = PATTERN.borrow(cs).borrow_mut();
+        if pattern.state == State::Playing {
+            return Err(ErrorCode::Busy);
+        }
+        pattern
+            .steps
+            .extend_from_slice(steps)
+            .map_err(|()| ErrorCode::InvalidArgument)?;
+        Ok(pattern.steps.len() as u16)
+    })
+}
+
+/// Start playing the pattern from its first step, which is driven right away. Every pin it drives has to be an output
+pub fn play(looping: bool) {
+    interrupt::free(|cs| {
+        let mut pattern = PATTERN.borrow(cs).borrow_mut();
+        pattern.looping = looping;
+        pattern.position = 0;
+        pattern.played = 0;
+        if pattern.steps.is_empty() {
+            pattern.state = State::Finished;
+            return;
+        }
+        pattern.tccr1a = tc1().tccr1a.read().bits();
+        pattern.tccr1b = tc1().tccr1b.read().bits();
+        pattern.state = State::Playing;
+        pattern.advance();
+
+        // Clear timer on compare match, so OCR1A sets the tick. The compare outputs are disconnected
+        tc1().tccr1a.write(|w| unsafe { w.bits(0) });
+        tc1().ocr1a.write(|w| unsafe { w.bits(pattern.compare) });
+        tc1().tcnt1.write(|w| unsafe { w.bits(0) });
+        tc1()
+            .tccr1b
+            .write(|w| unsafe { w.bits(TCCR1B_WGM_CTC | pattern.clock_select) });
+        tc1().timsk1.write(|w| unsafe { w.bits(TIMSK1_OCIE1A) });
+    });
+}
+
+/// Stop playing. Returns the number of steps played if the playback wasn't reported as done yet
+pub fn stop() -> Option<u32> {
+    interrupt::free(|cs| {
+        let mut pattern = PATTERN.borrow(cs).borrow_mut();
+        if pattern.state == State::Playing {
+            pattern.finish();
+        }
+        take_finished(&mut pattern)
+    })
+}
+
+/// The number of steps played, once the playback is over and only once
+pub fn finished() -> Option<u32> {
+    interrupt::free(|cs| take_finished(&mut PATTERN.borrow(cs).borrow_mut()))
+}
+
+fn take_finished(pattern: &mut Pattern) -> Option<u32> {
+    (pattern.state == State::Finished).then(|| {
+        pattern.state = State::Idle;
+        pattern.played
+    })
+}
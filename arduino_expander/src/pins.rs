@@ -1,60 +1,248 @@
+use crate::{
+    analog::AnalogInput,
+    blink, clock,
+    debounce::Debouncer,
+    eeprom::Eeprom,
+    events::PinChangeInterrupts,
+    failsafe, frequency,
+    labels::LabelTable,
+    pattern,
+    ports::{self, PortPin},
+    power_on::PowerOn,
+    pwm::PwmChannel,
+    stream,
+};
 use arduino_hal::hal::port::{
     mode::{Floating, Input, Output, PullUp},
     Pin,
 };
 use core::{cell::Cell, fmt, str::FromStr};
-use embedded_hal::digital::v2::{self as hal_digital, OutputPin};
-use gpio_actions::{PinLabel, PinName, PinState};
-use heapless::FnvIndexMap;
+use gpio_actions::{
+    transition, Blink, CaptureChunk, Debounce, Edge, ErrorCode, PatternSteps, PinBits, PinCall, PinCapabilities,
+    PinInfo, PinLabel, PinMask, PinMode, PinName, PinState, Port, PulseTrain, PwmPrescaler, CAPTURE_CHUNK_LEN,
+    MAX_BLOCKING_US, MAX_CAPTURE_EDGES, MAX_GATE_MS, PATTERN_STEPS_LEN,
+};
+use heapless::Vec;
 
-fn convert_state(state: PinState) -> hal_digital::PinState {
-    match state {
-        PinState::High => hal_digital::PinState::High,
-        PinState::Low => hal_digital::PinState::Low,
-    }
-}
+/// Most pins a [`PinDispatcher`] holds, which is every pin of the Uno besides the two the serial link uses. Every
+/// slot costs RAM whether it's used or not
+pub const MAX_PINS: usize = 18;
 
 enum StatefulPin<T> {
-    Input(Pin<Input<PullUp>, T>),
+    Floating(Pin<Input<Floating>, T>),
+    PullUp(Pin<Input<PullUp>, T>),
     Output(Pin<Output, T>),
 }
 
 impl<T> StatefulPin<T>
 where
-    T: avr_hal_generic::port::PinOps,
+    T: avr_hal_generic::port::PinOps + PortPin,
 {
+    fn floating(self) -> Self {
+        match self {
+            StatefulPin::Floating(floating_pin) => StatefulPin::Floating(floating_pin),
+            StatefulPin::PullUp(input_pin) => StatefulPin::Floating(input_pin.into_floating_input()),
+            StatefulPin::Output(output_pin) => StatefulPin::Floating(output_pin.into_floating_input()),
+        }
+    }
+
+    fn pull_up(self) -> Self {
+        match self {
+            StatefulPin::Floating(floating_pin) => StatefulPin::PullUp(floating_pin.into_pull_up_input()),
+            StatefulPin::PullUp(input_pin) => StatefulPin::PullUp(input_pin),
+            StatefulPin::Output(output_pin) => StatefulPin::PullUp(output_pin.into_pull_up_input()),
+        }
+    }
+
+    /// The bits the pin's registers are set to right now
+    fn bits(&self) -> PinBits {
+        match self {
+            StatefulPin::Floating(_) => PinBits::FLOATING,
+            StatefulPin::PullUp(_) => PinBits::PULL_UP,
+            StatefulPin::Output(output_pin) => PinBits {
+                output: true,
+                high: output_pin.is_set_high(),
+            },
+        }
+    }
+
+    /// Set the pin's registers to `bits`. They're written in the order [`transition`] relies on first, PORT before DDR
+    /// on the way to an output and DDR before PORT on the way to an input. The conversion [`PinBits::call`] picks then
+    /// writes the values the registers hold already, which brings the type state up to date whatever order avr-hal
+    /// uses. `into_output` clears PORT, so only `into_output_high` keeps the level a high output needs
+    fn step(self, bits: PinBits) -> Self {
+        let mask = 1 << T::BIT;
+        let level = if bits.high { mask } else { 0 };
+        let direction = if bits.output { mask } else { 0 };
+        if bits.output {
+            ports::write(T::PORT, mask, level);
+            ports::write_direction(T::PORT, mask, direction);
+        } else {
+            ports::write_direction(T::PORT, mask, direction);
+            ports::write(T::PORT, mask, level);
+        }
+        match bits.call() {
+            PinCall::FloatingInput => self.floating(),
+            PinCall::PullUpInput => self.pull_up(),
+            PinCall::Output => StatefulPin::Output(match self {
+                StatefulPin::Floating(floating_pin) => floating_pin.into_output(),
+                StatefulPin::PullUp(input_pin) => input_pin.into_output(),
+                StatefulPin::Output(output_pin) => output_pin.into_output(),
+            }),
+            PinCall::OutputHigh => StatefulPin::Output(match self {
+                StatefulPin::Floating(floating_pin) => floating_pin.into_output_high(),
+                StatefulPin::PullUp(input_pin) => input_pin.into_output_high(),
+                StatefulPin::Output(output_pin) => output_pin.into_output_high(),
+            }),
+        }
+    }
+
+    /// Set the pin's registers to `bits` without driving any level in between, see [`transition`]
+    fn transition(self, bits: PinBits) -> Self {
+        let from = self.bits();
+        transition(from, bits).fold(self, Self::step)
+    }
+
     fn output_state(self, state: PinState) -> Self {
-        let mut output_pin = match self {
-            StatefulPin::Input(input_pin) => input_pin.into_output(),
-            StatefulPin::Output(output_pin) => output_pin,
-        };
-        output_pin.set_state(convert_state(state)).unwrap();
-        StatefulPin::Output(output_pin)
+        self.transition(PinBits::output(state))
     }
 
-    fn input(self, is_high: &mut bool) -> Self {
-        let input_pin = match self {
-            StatefulPin::Input(input_pin) => input_pin,
-            StatefulPin::Output(output_pin) => output_pin.into_pull_up_input(),
+    /// Make the pin an output that keeps the level of its pull-up, high if it was enabled and low otherwise
+    fn output(self) -> Self {
+        let high = self.bits().high;
+        self.transition(PinBits { output: true, high })
+    }
+
+    fn is_high(&self) -> bool {
+        match self {
+            StatefulPin::Floating(floating_pin) => floating_pin.is_high(),
+            StatefulPin::PullUp(input_pin) => input_pin.is_high(),
+            StatefulPin::Output(output_pin) => output_pin.is_set_high(),
+        }
+    }
+
+    /// Wait for the pin to change to `state` and measure how long it stays there in µs, like pulseIn on the Arduino
+    /// core. Fails with [`ErrorCode::Timeout`] if the pulse didn't start and end within `timeout_us`, or with
+    /// [`ErrorCode::Aborted`] if the failsafe expired first
+    fn pulse_in(&self, state: PinState, timeout_us: u32) -> Result<u32, ErrorCode> {
+        let high = state == PinState::High;
+        let start = clock::micros();
+        let mut watch = failsafe::Watch::default();
+        let mut check = || {
+            if watch.expired() {
+                Err(ErrorCode::Aborted)
+            } else if clock::micros().wrapping_sub(start) >= timeout_us {
+                Err(ErrorCode::Timeout)
+            } else {
+                Ok(())
+            }
         };
-        *is_high = input_pin.is_high();
-        StatefulPin::Input(input_pin)
+
+        // A pulse that is already going on can't be measured completely
+        while self.is_high() == high {
+            check()?;
+        }
+        while self.is_high() != high {
+            check()?;
+        }
+        let pulse_start = clock::micros();
+        while self.is_high() == high {
+            check()?;
+        }
+        Ok(clock::micros().wrapping_sub(pulse_start))
     }
 }
 
 pub struct MutablePin<T> {
     pin: Cell<Option<StatefulPin<T>>>,
+    mode: PinMode,
     name: &'static str,
+    analog_channel: Option<u8>,
+    pwm_channel: Option<PwmChannel>,
+    subscription: Option<Edge>,
+    debouncer: Option<Debouncer>,
+    safe_state: Option<PinState>,
 }
 
 impl<T> MutablePin<T>
 where
-    T: avr_hal_generic::port::PinOps,
+    T: avr_hal_generic::port::PinOps + PortPin,
 {
     pub fn new(pin: Pin<Input<Floating>, T>, name: &'static str) -> Self {
         Self {
-            pin: Cell::new(Some(StatefulPin::Input(pin.into_pull_up_input()))),
+            pin: Cell::new(Some(StatefulPin::PullUp(pin.into_pull_up_input()))),
+            mode: PinMode::PullUp,
             name,
+            analog_channel: None,
+            pwm_channel: None,
+            subscription: None,
+            debouncer: None,
+            safe_state: None,
+        }
+    }
+
+    /// Mark the pin as connected to the ADC multiplexer input `channel`
+    pub fn with_analog_channel(mut self, channel: u8) -> Self {
+        self.analog_channel = Some(channel);
+        self
+    }
+
+    /// Mark the pin as an output compare pin of a timer, so it can generate PWM signals
+    pub fn with_pwm_channel(mut self, channel: PwmChannel) -> Self {
+        self.pwm_channel = Some(channel);
+        self
+    }
+
+    /// A PWM signal overrides whatever the pin is set to, so it has to be stopped before any other mode is used
+    fn leave_pwm_mode(&mut self) {
+        if let (PinMode::Pwm, Some(channel)) = (self.mode, self.pwm_channel) {
+            channel.disconnect();
+        }
+    }
+
+    /// Set the pin's level without stopping it from blinking
+    fn drive(&mut self, state: PinState) {
+        self.leave_pwm_mode();
+        self.mode = self.mode.after_output();
+        if self.mode == PinMode::OpenDrain && state == PinState::High {
+            // Releasing the line means not driving it at all
+            self.update(|pin| pin.transition(PinBits::FLOATING));
+        } else {
+            self.update(|pin| pin.output_state(state));
+        }
+    }
+
+    fn update(&mut self, transition: impl FnOnce(StatefulPin<T>) -> StatefulPin<T>) {
+        self.pin.set(Some(transition(self.pin.take().unwrap())))
+    }
+
+    /// Take the pin back from the blink module, which changes its registers behind the type state's back. Writing them
+    /// with the values they already have brings the type state up to date without changing the pin
+    fn stop_blinking(&mut self) {
+        if blink::stop(T::PORT, T::BIT) {
+            let bits = ports::bits(T::PORT, T::BIT);
+            self.update(|pin| pin.step(bits));
+        }
+    }
+
+    /// Switch to an input if the pin is an output right now
+    fn enter_input_mode(&mut self) {
+        let mode = self.mode.after_input();
+        if mode != self.mode {
+            // Switching to an input can't fail
+            self.set_mode(mode).unwrap_or_default();
+        }
+    }
+
+    /// The level of the pin right now, without debouncing
+    fn raw_level(&self) -> PinState {
+        let pin = self.pin.take().unwrap();
+        let is_high = pin.is_high();
+        self.pin.set(Some(pin));
+        if is_high {
+            PinState::High
+        } else {
+            PinState::Low
         }
     }
 }
@@ -71,73 +259,765 @@ where
 pub trait IOPin: fmt::Debug {
     fn output_state(&mut self, state: PinState);
     fn input(&mut self) -> PinState;
+    /// Like `input`, but waits for a pulse of `state` and measures its width, see [`StatefulPin::pulse_in`]
+    fn pulse_in(&mut self, state: PinState, timeout_us: u32) -> Result<u32, ErrorCode>;
+    fn set_mode(&mut self, mode: PinMode) -> Result<(), ErrorCode>;
+    fn pwm(&mut self, duty: u8) -> Result<(), ErrorCode>;
+    fn mode(&self) -> PinMode;
     fn name(&self) -> PinName;
+    fn analog_channel(&self) -> Option<u8>;
+    fn pwm_channel(&self) -> Option<PwmChannel>;
+    fn port(&self) -> (Port, u8);
+    fn subscription(&self) -> Option<Edge>;
+    fn subscribe(&mut self, edge: Option<Edge>);
+    fn debounce(&mut self, config: Debounce);
+    fn debounce_config(&self) -> Debounce;
+    /// Feed the current level to the debouncer. Returns the new level if it just settled
+    fn sample(&mut self, now: u32) -> Option<PinState>;
+    /// Drive the pin to `state` for `width_us`, then to the opposite state. Returns false if the pulse was cut short,
+    /// because the failsafe expired in the meantime
+    fn pulse(&mut self, state: PinState, width_us: u32) -> bool;
+    /// Start blinking in the background, or stop if `config` is `None`
+    fn blink(&mut self, config: Option<Blink>) -> Result<(), ErrorCode>;
+    /// The level the failsafe drives the pin to if it's an output, or `None` to make it an input
+    fn safe_state(&self) -> Option<PinState>;
+    fn set_safe_state(&mut self, state: Option<PinState>);
+    /// The level an output drives or an input reads, without switching the pin to an input like `input` does
+    fn query(&self) -> PinState;
 }
 
 impl<T> IOPin for MutablePin<T>
 where
-    T: avr_hal_generic::port::PinOps,
+    T: avr_hal_generic::port::PinOps + PortPin,
 {
     fn output_state(&mut self, state: PinState) {
-        self.pin.set(Some(self.pin.take().unwrap().output_state(state)))
+        self.stop_blinking();
+        self.drive(state);
     }
 
     fn input(&mut self) -> PinState {
-        let mut is_high = false;
-        self.pin.set(Some(self.pin.take().unwrap().input(&mut is_high)));
-        if is_high {
-            PinState::High
-        } else {
-            PinState::Low
+        self.stop_blinking();
+        self.enter_input_mode();
+
+        match &self.debouncer {
+            Some(debouncer) => debouncer.stable(),
+            None => self.raw_level(),
+        }
+    }
+
+    fn pulse_in(&mut self, state: PinState, timeout_us: u32) -> Result<u32, ErrorCode> {
+        self.stop_blinking();
+        self.enter_input_mode();
+        let pin = self.pin.take().unwrap();
+        let width = pin.pulse_in(state, timeout_us);
+        self.pin.set(Some(pin));
+        width
+    }
+
+    fn set_mode(&mut self, mode: PinMode) -> Result<(), ErrorCode> {
+        self.stop_blinking();
+        self.leave_pwm_mode();
+        match mode {
+            // Open-drain pins start out released
+            PinMode::Floating | PinMode::OpenDrain => self.update(|pin| pin.transition(PinBits::FLOATING)),
+            PinMode::PullUp => self.update(|pin| pin.transition(PinBits::PULL_UP)),
+            // Like pinMode on the Arduino core, a pull-up input becomes a high output, and a floating one a low output
+            PinMode::Output => self.update(|pin| pin.output()),
+            // Like analogWrite on the Arduino core, a new PWM pin starts out with a duty cycle of 0
+            PinMode::Pwm => return self.pwm(0),
+        }
+        self.mode = mode;
+        // Whatever the pin read before has nothing to do with its level in the new mode
+        let level = self.raw_level();
+        if let Some(debouncer) = self.debouncer.as_mut() {
+            debouncer.reset(level);
+        }
+        Ok(())
+    }
+
+    fn pwm(&mut self, duty: u8) -> Result<(), ErrorCode> {
+        let channel = self.pwm_channel.ok_or(ErrorCode::InvalidMode)?;
+        if stream::uses(channel) && stream::is_running() || pattern::uses(channel) && pattern::is_playing() {
+            return Err(ErrorCode::Busy);
+        }
+        self.stop_blinking();
+        match duty {
+            // Fast PWM always has a spike at the start of the period, so we drive the extremes directly, like the
+            // Arduino core does
+            0 | u8::MAX => {
+                channel.disconnect();
+                let state = if duty == 0 { PinState::Low } else { PinState::High };
+                self.update(|pin| pin.output_state(state));
+            }
+            _ => {
+                self.update(|pin| pin.output_state(PinState::Low));
+                channel.set_duty(duty);
+            }
         }
+        self.mode = PinMode::Pwm;
+        Ok(())
+    }
+
+    fn mode(&self) -> PinMode {
+        self.mode
     }
 
     fn name(&self) -> PinName {
         PinName::from_str(self.name).unwrap()
     }
+
+    fn analog_channel(&self) -> Option<u8> {
+        self.analog_channel
+    }
+
+    fn pwm_channel(&self) -> Option<PwmChannel> {
+        self.pwm_channel
+    }
+
+    fn port(&self) -> (Port, u8) {
+        (T::PORT, T::BIT)
+    }
+
+    fn subscription(&self) -> Option<Edge> {
+        self.subscription
+    }
+
+    fn subscribe(&mut self, edge: Option<Edge>) {
+        self.subscription = edge;
+    }
+
+    fn debounce(&mut self, config: Debounce) {
+        self.debouncer = match config {
+            Debounce::Off => None,
+            config => Some(Debouncer::new(config, self.raw_level())),
+        };
+    }
+
+    fn debounce_config(&self) -> Debounce {
+        self.debouncer.as_ref().map(Debouncer::config).unwrap_or_default()
+    }
+
+    fn sample(&mut self, now: u32) -> Option<PinState> {
+        let level = self.raw_level();
+        self.debouncer.as_mut()?.update(level, now)
+    }
+
+    fn pulse(&mut self, state: PinState, width_us: u32) -> bool {
+        if width_us <= clock::MAX_CRITICAL_MICROS {
+            // Interrupts would add a few µs of jitter, and the delay loop counts cycles exactly
+            avr_device::interrupt::free(|_| {
+                self.output_state(state);
+                arduino_hal::delay_us(width_us);
+                self.drive(!state);
+            });
+            true
+        } else {
+            let start = clock::micros();
+            self.output_state(state);
+            let done = clock::wait_until(start, width_us);
+            self.drive(!state);
+            done
+        }
+    }
+
+    fn blink(&mut self, config: Option<Blink>) -> Result<(), ErrorCode> {
+        self.stop_blinking();
+        let config = match config {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        blink::check(config)?;
+        self.drive(PinState::Low);
+        blink::start(T::PORT, T::BIT, self.mode == PinMode::OpenDrain, config);
+        Ok(())
+    }
+
+    fn safe_state(&self) -> Option<PinState> {
+        self.safe_state
+    }
+
+    fn set_safe_state(&mut self, state: Option<PinState>) {
+        self.safe_state = state;
+    }
+
+    fn query(&self) -> PinState {
+        match &self.debouncer {
+            Some(debouncer) if !self.mode.is_output() => debouncer.stable(),
+            _ => self.raw_level(),
+        }
+    }
+}
+
+/// Describe the pin at `index` in the list, labeled `pin_label`
+pub fn describe(index: usize, pin_label: PinLabel, pin: &dyn IOPin) -> PinInfo {
+    // Pin change interrupts work on every pin
+    let mut capabilities = PinCapabilities::DIGITAL.union(PinCapabilities::INTERRUPT);
+    if pin.analog_channel().is_some() {
+        capabilities = capabilities.union(PinCapabilities::ANALOG);
+    }
+    if pin.pwm_channel().is_some() {
+        capabilities = capabilities.union(PinCapabilities::PWM);
+    }
+    PinInfo {
+        index: index as u8,
+        label: pin_label,
+        name: pin.name(),
+        mode: pin.mode(),
+        capabilities,
+    }
 }
 
-type PinMap<'a> = FnvIndexMap<PinLabel, &'a mut dyn IOPin, 64>;
+/// Watch the pin for level changes as long as anything needs them
+fn update_watch(pin: &dyn IOPin, interrupts: &mut PinChangeInterrupts) {
+    let (port, bit) = pin.port();
+    let needed = pin.subscription().is_some()
+        || interrupts.is_counting(port, bit)
+        || interrupts.capturing() == Some((port, bit));
+    interrupts.watch(port, bit, needed);
+}
+
+/// A pin and the label it's addressed by. Pins without a label keep doing whatever they did, but can't be addressed
+struct Slot<'a> {
+    label: Option<PinLabel>,
+    /// The label the pin gets back on a factory reset
+    default_label: PinLabel,
+    pin: &'a mut dyn IOPin,
+}
 
 #[derive(Default)]
 pub struct PinDispatcher<'a> {
-    pin_map: PinMap<'a>,
+    pins: Vec<Slot<'a>, MAX_PINS>,
 }
 
 impl<'a> PinDispatcher<'a> {
     pub fn new() -> Self {
-        PinDispatcher { pin_map: PinMap::new() }
+        PinDispatcher { pins: Vec::new() }
     }
 
     pub fn add_pin(&mut self, pin_label: PinLabel, pin: &'a mut dyn IOPin) {
-        let maybe_previous_pin = self.pin_map.insert(pin_label, pin).unwrap();
-        if maybe_previous_pin.is_some() {
+        if self.has_pin(pin_label) {
             panic!("Inserting pin failed because the pin_label was already in use.")
         }
+        let slot = Slot {
+            label: Some(pin_label),
+            default_label: pin_label,
+            pin,
+        };
+        if self.pins.push(slot).is_err() {
+            panic!("Inserting pin failed because there are too many pins.")
+        }
     }
 
-    pub fn output(&mut self, pin_label: PinLabel, state: PinState) {
-        self.get_pin(pin_label).output_state(state);
+    /// Replace the labels of all pins with the ones stored in `eeprom`, if any were stored
+    pub fn load_labels(&mut self, eeprom: &mut Eeprom) {
+        if let Some(table) = LabelTable::load(eeprom) {
+            for slot in self.pins.iter_mut() {
+                let (port, bit) = slot.pin.port();
+                slot.label = table.get(port, bit);
+            }
+        }
+    }
+
+    /// Give the pin named `name` the label, replacing the one it had. Another pin can't have the same label
+    pub fn label(&mut self, pin_label: PinLabel, name: PinName, eeprom: &mut Eeprom) -> Result<(), ErrorCode> {
+        let index = self.pins.iter().position(|slot| slot.pin.name() == name);
+        let index = index.ok_or(ErrorCode::UnknownPin)?;
+        let taken = self
+            .pins
+            .iter()
+            .enumerate()
+            .any(|(other, slot)| other != index && slot.label == Some(pin_label));
+        // Zero marks pins without a label in EEPROM
+        if taken || pin_label == '\0' {
+            return Err(ErrorCode::InvalidArgument);
+        }
+        self.pins[index].label = Some(pin_label);
+        self.store_labels(eeprom);
+        Ok(())
+    }
+
+    /// Take the label away from the pin, which stops reporting events. Returns the name of the pin
+    pub fn unlabel(
+        &mut self,
+        pin_label: PinLabel,
+        interrupts: &mut PinChangeInterrupts,
+        eeprom: &mut Eeprom,
+    ) -> Result<PinName, ErrorCode> {
+        let slot = self.slot(pin_label)?;
+        slot.label = None;
+        slot.pin.subscribe(None);
+        update_watch(&*slot.pin, interrupts);
+        let name = slot.pin.name();
+        self.store_labels(eeprom);
+        Ok(name)
+    }
+
+    /// Give the pin labeled `pin_label` the label `new_label` instead
+    pub fn relabel(&mut self, pin_label: PinLabel, new_label: PinLabel, eeprom: &mut Eeprom) -> Result<(), ErrorCode> {
+        if new_label != pin_label && self.has_pin(new_label) || new_label == '\0' {
+            return Err(ErrorCode::InvalidArgument);
+        }
+        self.slot(pin_label)?.label = Some(new_label);
+        self.store_labels(eeprom);
+        Ok(())
+    }
+
+    /// Store the labels the pins get on a factory reset in `eeprom`. They keep their current labels until then
+    pub fn reset_labels(&mut self, eeprom: &mut Eeprom) {
+        let mut table = LabelTable::default();
+        for slot in &self.pins {
+            let (port, bit) = slot.pin.port();
+            table.set(port, bit, Some(slot.default_label));
+        }
+        table.store(eeprom);
+    }
+
+    fn store_labels(&mut self, eeprom: &mut Eeprom) {
+        let mut table = LabelTable::default();
+        for slot in &self.pins {
+            let (port, bit) = slot.pin.port();
+            table.set(port, bit, slot.label);
+        }
+        table.store(eeprom);
+    }
+
+    pub fn output(&mut self, pin_label: PinLabel, state: PinState) -> Result<(), ErrorCode> {
+        self.get_pin(pin_label)?.output_state(state);
+        Ok(())
+    }
+
+    pub fn input(&mut self, pin_label: PinLabel) -> Result<PinState, ErrorCode> {
+        Ok(self.get_pin(pin_label)?.input())
+    }
+
+    pub fn set_mode(&mut self, pin_label: PinLabel, mode: PinMode) -> Result<(), ErrorCode> {
+        self.get_pin(pin_label)?.set_mode(mode)
+    }
+
+    pub fn query(&mut self, pin_label: PinLabel) -> Result<(PinMode, PinState), ErrorCode> {
+        let pin = self.get_pin(pin_label)?;
+        Ok((pin.mode(), pin.query()))
+    }
+
+    pub fn analog_read(&mut self, pin_label: PinLabel, analog_input: &mut AnalogInput) -> Result<u16, ErrorCode> {
+        let pin = self.get_pin(pin_label)?;
+        let channel = pin.analog_channel().ok_or(ErrorCode::InvalidMode)?;
+        // The pull-up would skew the measurement
+        pin.set_mode(PinMode::Floating)?;
+        Ok(analog_input.read(channel))
+    }
+
+    pub fn pwm(&mut self, pin_label: PinLabel, duty: u8) -> Result<(), ErrorCode> {
+        self.get_pin(pin_label)?.pwm(duty)
+    }
+
+    pub fn configure_pwm(&mut self, pin_label: PinLabel, prescaler: PwmPrescaler) -> Result<(), ErrorCode> {
+        let channel = self.get_pin(pin_label)?.pwm_channel().ok_or(ErrorCode::InvalidMode)?;
+        if stream::uses(channel) && stream::is_running() || pattern::uses(channel) && pattern::is_playing() {
+            return Err(ErrorCode::Busy);
+        }
+        channel.set_prescaler(prescaler)
+    }
+
+    /// Read every pin in `mask` in one pass. Returns the pins that were actually read and their levels
+    pub fn input_many(&mut self, mask: PinMask) -> (PinMask, PinMask) {
+        let mut read = PinMask::NONE;
+        let mut levels = PinMask::NONE;
+        for (index, pin) in self.listed_mut().enumerate() {
+            if mask.contains(index) {
+                read = read.union(PinMask::single(index));
+                if pin.input() == PinState::High {
+                    levels = levels.union(PinMask::single(index));
+                }
+            }
+        }
+        (read, levels)
+    }
+
+    /// Set every pin in `mask` to its bit in `levels` in one pass. Returns the pins that were actually written
+    pub fn output_many(&mut self, mask: PinMask, levels: PinMask) -> PinMask {
+        let mut written = PinMask::NONE;
+        for (index, pin) in self.listed_mut().enumerate() {
+            if mask.contains(index) {
+                written = written.union(PinMask::single(index));
+                pin.output_state(if levels.contains(index) {
+                    PinState::High
+                } else {
+                    PinState::Low
+                });
+            }
+        }
+        written
+    }
+
+    /// Start sampling every pin in `mask` each `period_us`, see [`stream::start`]. Returns the pins that are actually
+    /// sampled and the period that's actually used. Nothing is sampled if none of the pins exist
+    pub fn stream(&mut self, mask: PinMask, period_us: u16) -> Result<(PinMask, u16), ErrorCode> {
+        let mut streamed = PinMask::NONE;
+        let mut pins = Vec::new();
+        for (index, pin) in self.listed().enumerate() {
+            if mask.contains(index) {
+                streamed = streamed.union(PinMask::single(index));
+                pins.push(pin.port()).unwrap_or_default();
+            }
+        }
+        if pins.is_empty() {
+            return Ok((PinMask::NONE, 0));
+        }
+        // The timer can't generate a PWM signal and time the samples at once, no matter which pins are sampled
+        if self.pwm_in_use(stream::uses) {
+            return Err(ErrorCode::Busy);
+        }
+        Ok((streamed, stream::start(pins, period_us)?))
+    }
+
+    /// Empty the pattern and let it drive the pins in `mask`, see [`pattern::load`]. Returns the pins that are actually
+    /// driven and the tick that's actually used
+    pub fn load_pattern(&mut self, mask: PinMask, tick_us: u32) -> Result<(PinMask, u32), ErrorCode> {
+        let mut driven = PinMask::NONE;
+        let mut port_mask = [0; 3];
+        for (index, pin) in self.listed().enumerate() {
+            if mask.contains(index) {
+                driven = driven.union(PinMask::single(index));
+                let (port, bit) = pin.port();
+                port_mask[ports::index(port)] |= 1 << bit;
+            }
+        }
+        Ok((driven, pattern::load(port_mask, tick_us)?))
+    }
+
+    /// Add `steps` to the end of the pattern. Returns the length of the pattern
+    pub fn append_pattern(&mut self, steps: PatternSteps) -> Result<u16, ErrorCode> {
+        let levels = steps
+            .levels
+            .get(..steps.count as usize)
+            .ok_or(ErrorCode::InvalidArgument)?;
+        let mut port_steps: Vec<pattern::Step, PATTERN_STEPS_LEN> = Vec::new();
+        for levels in levels {
+            // Pins the pattern doesn't drive are masked out when it's played
+            let mut step = [0; 3];
+            for (index, pin) in self.listed().enumerate() {
+                if levels.contains(index) {
+                    let (port, bit) = pin.port();
+                    step[ports::index(port)] |= 1 << bit;
+                }
+            }
+            port_steps.push(step).unwrap_or_default();
+        }
+        pattern::append(&port_steps)
+    }
+
+    /// Play the pattern, see [`pattern::play`]. Every pin the pattern drives has to be an output
+    pub fn play(&mut self, looping: bool) -> Result<(), ErrorCode> {
+        let mask = pattern::mask();
+        for Slot { pin, .. } in &self.pins {
+            let (port, bit) = pin.port();
+            if mask[ports::index(port)] & 1 << bit != 0 && pin.mode() != PinMode::Output {
+                return Err(ErrorCode::InvalidMode);
+            }
+        }
+        if self.pwm_in_use(pattern::uses) {
+            return Err(ErrorCode::Busy);
+        }
+        pattern::play(looping);
+        Ok(())
+    }
+
+    /// Set every pin in `mask` to its bit in `levels` at the same instant. Only works if all of them are outputs on
+    /// the same port. Returns the pins that were actually written
+    pub fn output_atomic(&mut self, mask: PinMask, levels: PinMask) -> Result<PinMask, ErrorCode> {
+        let mut port = None;
+        let mut written = PinMask::NONE;
+        let mut port_mask = 0;
+        let mut port_levels = 0;
+        for (index, pin) in self.listed().enumerate() {
+            if !mask.contains(index) {
+                continue;
+            }
+            // Anything but a push-pull output would need its direction changed, which can't happen in the same write
+            if pin.mode() != PinMode::Output {
+                return Err(ErrorCode::InvalidMode);
+            }
+            let (pin_port, bit) = pin.port();
+            if *port.get_or_insert(pin_port) != pin_port {
+                return Err(ErrorCode::PortMismatch);
+            }
+            written = written.union(PinMask::single(index));
+            port_mask |= 1 << bit;
+            if levels.contains(index) {
+                port_levels |= 1 << bit;
+            }
+        }
+        for (index, pin) in self.listed_mut().enumerate() {
+            if written.contains(index) {
+                // Like with Output, the new level is here to stay, so blinking mustn't overwrite it
+                pin.blink(None).unwrap_or_default();
+            }
+        }
+        if let Some(port) = port {
+            ports::write(port, port_mask, port_levels);
+        }
+        Ok(written)
+    }
+
+    /// The pins that sit on `port`
+    pub fn port_pins(&self, port: Port) -> PinMask {
+        self.listed()
+            .enumerate()
+            .filter(|(_, pin)| pin.port().0 == port)
+            .fold(PinMask::NONE, |mask, (index, _)| mask.union(PinMask::single(index)))
     }
 
-    pub fn input(&mut self, pin_label: PinLabel) -> PinState {
-        self.get_pin(pin_label).input()
+    /// Report level changes of the pin matching `edge` from now on, or stop reporting them if `edge` is `None`
+    pub fn subscribe(
+        &mut self,
+        pin_label: PinLabel,
+        edge: Option<Edge>,
+        interrupts: &mut PinChangeInterrupts,
+    ) -> Result<(), ErrorCode> {
+        let pin = self.get_pin(pin_label)?;
+        pin.subscribe(edge);
+        update_watch(pin, interrupts);
+        Ok(())
+    }
+
+    /// Count edges of the pin matching `edge` from zero, or stop counting if `edge` is `None`
+    pub fn count_edges(
+        &mut self,
+        pin_label: PinLabel,
+        edge: Option<Edge>,
+        interrupts: &mut PinChangeInterrupts,
+    ) -> Result<(), ErrorCode> {
+        let pin = self.get_pin(pin_label)?;
+        let (port, bit) = pin.port();
+        interrupts.count(port, bit, edge);
+        update_watch(pin, interrupts);
+        Ok(())
+    }
+
+    /// Record up to `limit` edges of the pin in the background, or stop if `limit` is zero.
+    /// Replaces any capture that was running on another pin
+    pub fn capture(
+        &mut self,
+        pin_label: PinLabel,
+        limit: u16,
+        interrupts: &mut PinChangeInterrupts,
+    ) -> Result<(), ErrorCode> {
+        if limit > MAX_CAPTURE_EDGES {
+            return Err(ErrorCode::InvalidArgument);
+        }
+        let (port, bit) = self.get_pin(pin_label)?.port();
+        let previous = interrupts.capturing();
+        interrupts.capture(port, bit, limit);
+        for Slot { pin, .. } in &self.pins {
+            if pin.port() == (port, bit) || Some(pin.port()) == previous {
+                update_watch(&**pin, interrupts);
+            }
+        }
+        Ok(())
+    }
+
+    /// The edges recorded on the pin so far, split up so each fits into a [`gpio_actions::Response::Capture`]
+    pub fn read_capture<'i>(
+        &mut self,
+        pin_label: PinLabel,
+        interrupts: &'i PinChangeInterrupts,
+    ) -> Result<impl Iterator<Item = CaptureChunk> + 'i, ErrorCode> {
+        let (port, bit) = self.get_pin(pin_label)?.port();
+        let total = interrupts.capture_len(port, bit).ok_or(ErrorCode::InvalidMode)?;
+        // Even an empty capture is sent as one chunk, so the host learns that there's nothing
+        let offsets = (0..total.max(1)).step_by(CAPTURE_CHUNK_LEN);
+        Ok(offsets.filter_map(move |offset| interrupts.read_capture(port, bit, offset, total)))
+    }
+
+    /// The number of edges counted so far and whether the count overflowed, see [`PinChangeInterrupts::read_count`]
+    pub fn read_count(
+        &mut self,
+        pin_label: PinLabel,
+        reset: bool,
+        interrupts: &mut PinChangeInterrupts,
+    ) -> Result<(u32, bool), ErrorCode> {
+        let (port, bit) = self.get_pin(pin_label)?.port();
+        if !interrupts.is_counting(port, bit) {
+            return Err(ErrorCode::InvalidMode);
+        }
+        Ok(interrupts.read_count(port, bit, reset))
+    }
+
+    /// Measure the frequency of the signal on the pin in Hz, see [`frequency::measure`]
+    pub fn measure_frequency(&mut self, pin_label: PinLabel, gate_ms: u16) -> Result<u32, ErrorCode> {
+        let pin = self.get_pin(pin_label)?;
+        if pin.port() != frequency::COUNTER_PIN {
+            return Err(ErrorCode::InvalidMode);
+        }
+        if !(1..=MAX_GATE_MS).contains(&gate_ms) {
+            return Err(ErrorCode::InvalidArgument);
+        }
+        // The pattern needs the same timer
+        if pattern::is_playing() {
+            return Err(ErrorCode::Busy);
+        }
+        // The timer counts whatever level the pin has, so it must not drive it itself
+        pin.input();
+        frequency::measure(gate_ms)
+    }
+
+    pub fn set_safe_state(&mut self, pin_label: PinLabel, state: Option<PinState>) -> Result<(), ErrorCode> {
+        self.get_pin(pin_label)?.set_safe_state(state);
+        Ok(())
+    }
+
+    /// Put every output into its safe state, because the host is gone. Inputs are left alone
+    pub fn failsafe(&mut self) {
+        for Slot { pin, .. } in self.pins.iter_mut() {
+            if !pin.mode().is_output() {
+                continue;
+            }
+            match pin.safe_state() {
+                Some(state) => pin.output_state(state),
+                None => pin.set_mode(PinMode::default()).unwrap_or_default(),
+            }
+        }
+    }
+
+    /// Put every pin into the mode and level it should have after a reset
+    pub fn apply_power_on(&mut self, power_on: &PowerOn) {
+        for Slot { pin, .. } in self.pins.iter_mut() {
+            let (port, bit) = pin.port();
+            match power_on.get(port, bit) {
+                // Switching to an output first would drive the pin low for a moment
+                (PinMode::Output, state) => pin.output_state(state),
+                (PinMode::OpenDrain, state) => {
+                    pin.set_mode(PinMode::OpenDrain).unwrap_or_default();
+                    pin.output_state(state);
+                }
+                (mode, _) => pin.set_mode(mode).unwrap_or_default(),
+            }
+        }
+    }
+
+    /// Store the mode and level the pin should have after a reset. Returns them as they were stored
+    pub fn set_power_on(
+        &mut self,
+        pin_label: PinLabel,
+        mode: PinMode,
+        state: PinState,
+        power_on: &mut PowerOn,
+        eeprom: &mut Eeprom,
+    ) -> Result<(PinMode, PinState), ErrorCode> {
+        let (port, bit) = self.get_pin(pin_label)?.port();
+        power_on.set(port, bit, mode, state, eeprom)?;
+        Ok(power_on.get(port, bit))
+    }
+
+    pub fn power_on(&mut self, pin_label: PinLabel, power_on: &PowerOn) -> Result<(PinMode, PinState), ErrorCode> {
+        let (port, bit) = self.get_pin(pin_label)?.port();
+        Ok(power_on.get(port, bit))
+    }
+
+    pub fn debounce(&mut self, pin_label: PinLabel, config: Debounce) -> Result<(), ErrorCode> {
+        self.get_pin(pin_label)?.debounce(config);
+        Ok(())
+    }
+
+    /// Generate all pulses of `train` on the pin, returning only once they're done
+    pub fn pulse_train(&mut self, pin_label: PinLabel, train: PulseTrain) -> Result<(), ErrorCode> {
+        let pin = self.get_pin(pin_label)?;
+        if train.count > 1 && train.period_us <= train.width_us {
+            return Err(ErrorCode::InvalidArgument);
+        }
+        // Nothing else is read in the meantime, not even the heartbeats
+        if train.duration_us() > MAX_BLOCKING_US as u64 {
+            return Err(ErrorCode::InvalidArgument);
+        }
+        for pulse in 0..train.count {
+            let start = clock::micros();
+            if !pin.pulse(train.state, train.width_us) {
+                return Err(ErrorCode::Aborted);
+            }
+            if pulse + 1 < train.count && !clock::wait_until(start, train.period_us) {
+                return Err(ErrorCode::Aborted);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn measure_pulse(&mut self, pin_label: PinLabel, state: PinState, timeout_us: u32) -> Result<u32, ErrorCode> {
+        let pin = self.get_pin(pin_label)?;
+        if timeout_us > MAX_BLOCKING_US {
+            return Err(ErrorCode::InvalidArgument);
+        }
+        pin.pulse_in(state, timeout_us)
+    }
+
+    pub fn blink(&mut self, pin_label: PinLabel, config: Blink) -> Result<(), ErrorCode> {
+        self.get_pin(pin_label)?.blink(Some(config))
+    }
+
+    /// Do everything pins do in the background at `now` milliseconds: Sample debounced pins, and call `emit` for every
+    /// level change of a subscribed pin since the last call. Debounced pins only report settled changes
+    pub fn poll(&mut self, interrupts: &mut PinChangeInterrupts, now: u32, mut emit: impl FnMut(PinLabel, PinState)) {
+        let edges = interrupts.take_edges();
+        for Slot { label, pin, .. } in self.pins.iter_mut() {
+            let settled = pin.sample(now);
+            // A PWM signal would flood the serial connection with events, and pins without a label can't report any
+            let pin_label = match label {
+                Some(pin_label) if pin.mode() != PinMode::Pwm => *pin_label,
+                _ => continue,
+            };
+            match (pin.subscription(), pin.debounce_config()) {
+                (None, _) => (),
+                (Some(edge), Debounce::Off) => {
+                    let (port, bit) = pin.port();
+                    for state in edges.transitions(port, bit, edge).into_iter().flatten() {
+                        emit(pin_label, state);
+                    }
+                }
+                (Some(edge), _) => {
+                    if let Some(state) = settled.filter(|&state| edge.matches(state)) {
+                        emit(pin_label, state);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The number of pins that have a label
+    pub fn pin_count(&self) -> u8 {
+        self.listed().count() as u8
     }
 
     pub fn has_pin(&self, pin_label: PinLabel) -> bool {
-        self.pin_map.contains_key(&pin_label)
+        self.pins.iter().any(|slot| slot.label == Some(pin_label))
     }
 
-    fn get_pin(&mut self, pin_label: PinLabel) -> &mut dyn IOPin {
-        *self.pin_map.get_mut(&pin_label).unwrap()
+    /// The pins that have a label, in the order they're listed. Masks refer to pins by their position in here
+    pub fn iter(&self) -> impl Iterator<Item = (PinLabel, &(dyn IOPin + 'a))> {
+        self.pins.iter().filter_map(|slot| Some((slot.label?, &*slot.pin)))
+    }
+
+    fn listed(&self) -> impl Iterator<Item = &(dyn IOPin + 'a)> {
+        self.iter().map(|(_, pin)| pin)
+    }
+
+    fn listed_mut(&mut self) -> impl Iterator<Item = &mut (dyn IOPin + 'a)> {
+        let labeled = self.pins.iter_mut().filter(|slot| slot.label.is_some());
+        labeled.map(|slot| &mut *slot.pin)
+    }
+
+    /// Whether any pin generates a PWM signal on one of the channels `uses` is true for
+    fn pwm_in_use(&self, uses: fn(PwmChannel) -> bool) -> bool {
+        let mut pins_with_pwm = self.pins.iter().filter(|slot| slot.pin.mode() == PinMode::Pwm);
+        pins_with_pwm.any(|slot| slot.pin.pwm_channel().map_or(false, uses))
+    }
+
+    fn slot(&mut self, pin_label: PinLabel) -> Result<&mut Slot<'a>, ErrorCode> {
+        let slot = self.pins.iter_mut().find(|slot| slot.label == Some(pin_label));
+        slot.ok_or(ErrorCode::UnknownPin)
     }
-}
 
-impl<'a, 'b> IntoIterator for &'a PinDispatcher<'b> {
-    type Item = <&'a PinMap<'b> as IntoIterator>::Item;
-    type IntoIter = <&'a PinMap<'b> as IntoIterator>::IntoIter;
-    fn into_iter(self) -> Self::IntoIter {
-        self.pin_map.iter()
+    fn get_pin(&mut self, pin_label: PinLabel) -> Result<&mut dyn IOPin, ErrorCode> {
+        Ok(&mut *self.slot(pin_label)?.pin)
     }
 }
 
@@ -147,4 +1027,13 @@ macro_rules! add_pin {
         let mut $name = $crate::pins::MutablePin::new($pins.$name, stringify!($name));
         $dispatcher.add_pin($tag, &mut $name);
     };
+    ($dispatcher:ident, $pins:ident.$name:ident, $tag:literal, analog $channel:literal) => {
+        let mut $name = $crate::pins::MutablePin::new($pins.$name, stringify!($name)).with_analog_channel($channel);
+        $dispatcher.add_pin($tag, &mut $name);
+    };
+    ($dispatcher:ident, $pins:ident.$name:ident, $tag:literal, pwm $channel:ident) => {
+        let mut $name = $crate::pins::MutablePin::new($pins.$name, stringify!($name))
+            .with_pwm_channel($crate::pwm::PwmChannel::$channel);
+        $dispatcher.add_pin($tag, &mut $name);
+    };
 }
@@ -0,0 +1,103 @@
+use arduino_hal::{
+    hal::port::{
+        PB0, PB1, PB2, PB3, PB4, PB5, PB6, PB7, PC0, PC1, PC2, PC3, PC4, PC5, PC6, PD0, PD1, PD2, PD3, PD4, PD5, PD6,
+        PD7,
+    },
+    pac::{portb, portc, portd, PORTB, PORTC, PORTD},
+};
+use avr_device::interrupt;
+use gpio_actions::{PinBits, Port};
+
+/// Where a pin sits in the I/O registers, so several pins on the same port can be written at once
+pub trait PortPin {
+    const PORT: Port;
+    const BIT: u8;
+}
+
+macro_rules! impl_port_pin {
+    ($port:ident: $($pin:ident = $bit:literal),+) => {
+        $(
+            impl PortPin for $pin {
+                const PORT: Port = Port::$port;
+                const BIT: u8 = $bit;
+            }
+        )+
+    };
+}
+
+impl_port_pin!(B: PB0 = 0, PB1 = 1, PB2 = 2, PB3 = 3, PB4 = 4, PB5 = 5, PB6 = 6, PB7 = 7);
+impl_port_pin!(C: PC0 = 0, PC1 = 1, PC2 = 2, PC3 = 3, PC4 = 4, PC5 = 5, PC6 = 6);
+impl_port_pin!(D: PD0 = 0, PD1 = 1, PD2 = 2, PD3 = 3, PD4 = 4, PD5 = 5, PD6 = 6, PD7 = 7);
+
+// The port peripherals are consumed by arduino_hal::pins!, so like in the pwm module, we access their registers
+// directly. Outside of a pin's own mode changes, only the output registers are written, and only for pins that are
+// outputs already, except for the direction of open-drain pins that blink. Pins catch up on those changes once they
+// stop blinking.
+fn portb() -> &'static portb::RegisterBlock {
+    unsafe { &*PORTB::ptr() }
+}
+
+fn portc() -> &'static portc::RegisterBlock {
+    unsafe { &*PORTC::ptr() }
+}
+
+fn portd() -> &'static portd::RegisterBlock {
+    unsafe { &*PORTD::ptr() }
+}
+
+/// Position of `port` in arrays that hold something for every port, in the order of [`Port::ALL`]
+pub fn index(port: Port) -> usize {
+    match port {
+        Port::B => 0,
+        Port::C => 1,
+        Port::D => 2,
+    }
+}
+
+/// Position of the pin at `bit` of `port` in arrays that hold something for every pin of each port
+pub fn pin_index(port: Port, bit: u8) -> usize {
+    index(port) * 8 + bit as usize
+}
+
+/// Set the pins in `mask` to their bits in `levels` with a single write to the output register of `port`
+pub fn write(port: Port, mask: u8, levels: u8) {
+    let update = |bits: u8| (bits & !mask) | (levels & mask);
+    // The pattern module writes these registers from an interrupt, which must not happen between reading and writing
+    interrupt::free(|_| match port {
+        Port::B => portb().portb.modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        Port::C => portc().portc.modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        Port::D => portd().portd.modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+    });
+}
+
+/// Set the direction of the pins in `mask`, making those with their bit set in `outputs` outputs
+pub fn write_direction(port: Port, mask: u8, outputs: u8) {
+    let update = |bits: u8| (bits & !mask) | (outputs & mask);
+    interrupt::free(|_| match port {
+        Port::B => portb().ddrb.modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        Port::C => portc().ddrc.modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        Port::D => portd().ddrd.modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+    });
+}
+
+/// What the registers of the pin at `bit` of `port` are set to right now
+pub fn bits(port: Port, bit: u8) -> PinBits {
+    let (ddr, output) = match port {
+        Port::B => (portb().ddrb.read().bits(), portb().portb.read().bits()),
+        Port::C => (portc().ddrc.read().bits(), portc().portc.read().bits()),
+        Port::D => (portd().ddrd.read().bits(), portd().portd.read().bits()),
+    };
+    PinBits {
+        output: ddr & 1 << bit != 0,
+        high: output & 1 << bit != 0,
+    }
+}
+
+/// The current input levels of all pins on `port`
+pub fn read(port: Port) -> u8 {
+    match port {
+        Port::B => portb().pinb.read().bits(),
+        Port::C => portc().pinc.read().bits(),
+        Port::D => portd().pind.read().bits(),
+    }
+}
@@ -0,0 +1,78 @@
+use crate::{
+    eeprom::{Eeprom, POWER_ON_ADDRESS},
+    ports,
+};
+use gpio_actions::{ErrorCode, PinMode, PinState, Port};
+
+// Each pin gets a byte with the mode in its lower bits and the level in its highest bit
+const UNSET: u8 = 0;
+const FLOATING: u8 = 1;
+const OUTPUT: u8 = 2;
+const OPEN_DRAIN: u8 = 3;
+const LEVEL_HIGH: u8 = 1 << 7;
+
+/// The mode and level of every pin after a reset, kept in EEPROM. Pins without a default stay pull-up inputs
+pub struct PowerOn {
+    /// One byte for every pin of each port, see [`ports::pin_index`]
+    pins: [u8; 24],
+}
+
+impl PowerOn {
+    /// The defaults stored in `eeprom`, or none at all if they were never stored or got corrupted
+    pub fn load(eeprom: &mut Eeprom) -> Self {
+        let mut pins = [UNSET; 24];
+        if !eeprom.read_record(POWER_ON_ADDRESS, &mut pins) {
+            pins = [UNSET; 24];
+        }
+        Self { pins }
+    }
+
+    /// The default of the pin at `bit` of `port`
+    pub fn get(&self, port: Port, bit: u8) -> (PinMode, PinState) {
+        let byte = self.pins[ports::pin_index(port, bit)];
+        let mode = match byte & !LEVEL_HIGH {
+            FLOATING => PinMode::Floating,
+            OUTPUT => PinMode::Output,
+            OPEN_DRAIN => PinMode::OpenDrain,
+            _ => return (PinMode::PullUp, PinState::Low),
+        };
+        if byte & LEVEL_HIGH != 0 {
+            (mode, PinState::High)
+        } else {
+            (mode, PinState::Low)
+        }
+    }
+
+    /// Store the default of the pin at `bit` of `port` in `eeprom`
+    pub fn set(
+        &mut self,
+        port: Port,
+        bit: u8,
+        mode: PinMode,
+        state: PinState,
+        eeprom: &mut Eeprom,
+    ) -> Result<(), ErrorCode> {
+        let mode = match mode {
+            PinMode::PullUp => UNSET,
+            PinMode::Floating => FLOATING,
+            PinMode::Output => OUTPUT,
+            PinMode::OpenDrain => OPEN_DRAIN,
+            // Without the timer being set up, there's nothing to generate the signal
+            PinMode::Pwm => return Err(ErrorCode::InvalidMode),
+        };
+        // Inputs have no level to remember
+        let level = match state {
+            PinState::High if mode == OUTPUT || mode == OPEN_DRAIN => LEVEL_HIGH,
+            _ => 0,
+        };
+        self.pins[ports::pin_index(port, bit)] = mode | level;
+        eeprom.write_record(POWER_ON_ADDRESS, &self.pins);
+        Ok(())
+    }
+
+    /// Forget all defaults
+    pub fn reset(&mut self, eeprom: &mut Eeprom) {
+        self.pins = [UNSET; 24];
+        eeprom.write_record(POWER_ON_ADDRESS, &self.pins);
+    }
+}
@@ -0,0 +1,132 @@
+use arduino_hal::pac::{tc0, tc1, tc2, TC0, TC1, TC2};
+use gpio_actions::{ErrorCode, PwmPrescaler};
+
+// Waveform generation mode 8-bit fast PWM, split across TCCRnA and TCCRnB like in the datasheet
+const TCCRA_WGM_FAST_PWM: u8 = 0b11;
+const TCCR1A_WGM_FAST_PWM_8BIT: u8 = 0b01;
+const TCCR1B_WGM_FAST_PWM_8BIT: u8 = 1 << 3;
+// Non-inverting compare output mode, the pin goes high at the start of each period
+const TCCRA_COMA1: u8 = 1 << 7;
+const TCCRA_COMB1: u8 = 1 << 5;
+const TCCRB_CS_MASK: u8 = 0b111;
+
+// PwmChannels are handed out to pins, so they can't own the timer peripherals. `init` takes the peripherals
+// instead, so nothing else can use them once the channels access the registers directly.
+fn tc0() -> &'static tc0::RegisterBlock {
+    unsafe { &*TC0::ptr() }
+}
+
+fn tc1() -> &'static tc1::RegisterBlock {
+    unsafe { &*TC1::ptr() }
+}
+
+fn tc2() -> &'static tc2::RegisterBlock {
+    unsafe { &*TC2::ptr() }
+}
+
+/// Put all timers into 8-bit fast PWM mode with a prescaler of 64, which results in ~980Hz like on the Arduino core
+pub fn init(_tc0: TC0, _tc1: TC1, _tc2: TC2) {
+    tc0().tccr0a.write(|w| unsafe { w.bits(TCCRA_WGM_FAST_PWM) });
+    tc1().tccr1a.write(|w| unsafe { w.bits(TCCR1A_WGM_FAST_PWM_8BIT) });
+    tc1().tccr1b.write(|w| unsafe { w.bits(TCCR1B_WGM_FAST_PWM_8BIT) });
+    tc2().tccr2a.write(|w| unsafe { w.bits(TCCRA_WGM_FAST_PWM) });
+    for channel in [PwmChannel::Timer0A, PwmChannel::Timer1A, PwmChannel::Timer2A] {
+        channel.set_prescaler(PwmPrescaler::default()).unwrap_or_default();
+    }
+}
+
+/// One of the two compare outputs of a timer, named like the OCnx pins in the datasheet
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PwmChannel {
+    Timer0A,
+    Timer0B,
+    Timer1A,
+    Timer1B,
+    Timer2A,
+    Timer2B,
+}
+
+impl PwmChannel {
+    /// Start generating a PWM signal with `duty`/256 on the pin. The pin has to be an output already
+    pub fn set_duty(self, duty: u8) {
+        match self {
+            PwmChannel::Timer0A => tc0().ocr0a.write(|w| unsafe { w.bits(duty) }),
+            PwmChannel::Timer0B => tc0().ocr0b.write(|w| unsafe { w.bits(duty) }),
+            PwmChannel::Timer1A => tc1().ocr1a.write(|w| unsafe { w.bits(duty as u16) }),
+            PwmChannel::Timer1B => tc1().ocr1b.write(|w| unsafe { w.bits(duty as u16) }),
+            PwmChannel::Timer2A => tc2().ocr2a.write(|w| unsafe { w.bits(duty) }),
+            PwmChannel::Timer2B => tc2().ocr2b.write(|w| unsafe { w.bits(duty) }),
+        }
+        let compare_output = self.compare_output_bit();
+        self.modify_tccra(|bits| bits | compare_output);
+    }
+
+    /// Stop the PWM signal, handing the pin back to its PORT register
+    pub fn disconnect(self) {
+        let compare_output = self.compare_output_bit();
+        self.modify_tccra(|bits| bits & !compare_output);
+    }
+
+    /// Change the prescaler of the timer. This also changes the frequency on the other channel of the same timer
+    pub fn set_prescaler(self, prescaler: PwmPrescaler) -> Result<(), ErrorCode> {
+        // Timer 0 also keeps the time, see the clock module
+        if matches!(self, PwmChannel::Timer0A | PwmChannel::Timer0B) && prescaler != PwmPrescaler::Prescale64 {
+            return Err(ErrorCode::InvalidArgument);
+        }
+
+        let clock_select = match self {
+            // Timer 2 has its own prescaler, which supports more divisors than the one of Timer 0 and 1
+            PwmChannel::Timer0A | PwmChannel::Timer0B | PwmChannel::Timer1A | PwmChannel::Timer1B => match prescaler {
+                PwmPrescaler::Direct => 0b001,
+                PwmPrescaler::Prescale8 => 0b010,
+                PwmPrescaler::Prescale64 => 0b011,
+                PwmPrescaler::Prescale256 => 0b100,
+                PwmPrescaler::Prescale1024 => 0b101,
+                PwmPrescaler::Prescale32 | PwmPrescaler::Prescale128 => return Err(ErrorCode::InvalidArgument),
+            },
+            PwmChannel::Timer2A | PwmChannel::Timer2B => match prescaler {
+                PwmPrescaler::Direct => 0b001,
+                PwmPrescaler::Prescale8 => 0b010,
+                PwmPrescaler::Prescale32 => 0b011,
+                PwmPrescaler::Prescale64 => 0b100,
+                PwmPrescaler::Prescale128 => 0b101,
+                PwmPrescaler::Prescale256 => 0b110,
+                PwmPrescaler::Prescale1024 => 0b111,
+            },
+        };
+        let set_clock = |bits: u8| (bits & !TCCRB_CS_MASK) | clock_select;
+        match self {
+            PwmChannel::Timer0A | PwmChannel::Timer0B => {
+                tc0().tccr0b.modify(|r, w| unsafe { w.bits(set_clock(r.bits())) })
+            }
+            PwmChannel::Timer1A | PwmChannel::Timer1B => {
+                tc1().tccr1b.modify(|r, w| unsafe { w.bits(set_clock(r.bits())) })
+            }
+            PwmChannel::Timer2A | PwmChannel::Timer2B => {
+                tc2().tccr2b.modify(|r, w| unsafe { w.bits(set_clock(r.bits())) })
+            }
+        }
+        Ok(())
+    }
+
+    fn compare_output_bit(self) -> u8 {
+        match self {
+            PwmChannel::Timer0A | PwmChannel::Timer1A | PwmChannel::Timer2A => TCCRA_COMA1,
+            PwmChannel::Timer0B | PwmChannel::Timer1B | PwmChannel::Timer2B => TCCRA_COMB1,
+        }
+    }
+
+    fn modify_tccra(self, modify: impl Fn(u8) -> u8) {
+        match self {
+            PwmChannel::Timer0A | PwmChannel::Timer0B => {
+                tc0().tccr0a.modify(|r, w| unsafe { w.bits(modify(r.bits())) })
+            }
+            PwmChannel::Timer1A | PwmChannel::Timer1B => {
+                tc1().tccr1a.modify(|r, w| unsafe { w.bits(modify(r.bits())) })
+            }
+            PwmChannel::Timer2A | PwmChannel::Timer2B => {
+                tc2().tccr2a.modify(|r, w| unsafe { w.bits(modify(r.bits())) })
+            }
+        }
+    }
+}
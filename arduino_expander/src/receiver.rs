@@ -0,0 +1,77 @@
+use crate::clock;
+use arduino_hal::pac::{usart0, USART0};
+use avr_device::interrupt::{self, Mutex};
+use core::cell::RefCell;
+
+const UCSR0B_RXCIE0: u8 = 1 << 7;
+// The USART only holds two bytes itself, which is nothing compared to the time it takes to send a response. This
+// holds a couple of frames, so hosts can send several actions without waiting for their responses
+const BUFFER_LEN: usize = 64;
+
+/// Bytes that were received, but not read yet
+struct Buffer {
+    bytes: [u8; BUFFER_LEN],
+    start: usize,
+    len: usize,
+    last_byte: u32,
+}
+
+impl Buffer {
+    /// Remember `byte`, received at `now` milliseconds. If the buffer is full it's dropped, which corrupts its frame,
+    /// so the whole frame is rejected
+    fn push(&mut self, byte: u8, now: u32) {
+        // Even a dropped byte shows that the host is still there, see `failsafe::expired`
+        self.last_byte = now;
+        if self.len < BUFFER_LEN {
+            self.bytes[(self.start + self.len) % BUFFER_LEN] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.start];
+        self.start = (self.start + 1) % BUFFER_LEN;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+static BUFFER: Mutex<RefCell<Buffer>> = Mutex::new(RefCell::new(Buffer {
+    bytes: [0; BUFFER_LEN],
+    start: 0,
+    len: 0,
+    last_byte: 0,
+}));
+
+// The USART is owned by the serial driver, which only ever sends once the interrupt below is enabled
+fn usart0() -> &'static usart0::RegisterBlock {
+    unsafe { &*USART0::ptr() }
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn USART_RX() {
+    // Reading the data register clears the interrupt flag
+    let byte = usart0().udr0.read().bits();
+    let now = clock::millis();
+    interrupt::free(|cs| BUFFER.borrow(cs).borrow_mut().push(byte, now));
+}
+
+/// Start receiving in the background. Must be called after the serial driver was set up, which resets the USART
+pub fn init() {
+    usart0()
+        .ucsr0b
+        .modify(|r, w| unsafe { w.bits(r.bits() | UCSR0B_RXCIE0) });
+}
+
+/// The oldest byte that was received and not read yet
+pub fn read() -> Option<u8> {
+    interrupt::free(|cs| BUFFER.borrow(cs).borrow_mut().pop())
+}
+
+/// When the last byte was received in milliseconds, even if it wasn't read yet
+pub fn last_byte() -> u32 {
+    interrupt::free(|cs| BUFFER.borrow(cs).borrow().last_byte)
+}
@@ -0,0 +1,134 @@
+use crate::{pins::MAX_PINS, ports, pwm::PwmChannel};
+use arduino_hal::pac::{tc2, TC2};
+use avr_device::interrupt::{self, Mutex};
+use core::cell::RefCell;
+use gpio_actions::{
+    min_sample_period_us, ErrorCode, Port, SampleFrame, MAX_SAMPLE_PERIOD_US, MIN_SAMPLE_PERIOD_US, SAMPLE_FRAME_BYTES,
+};
+use heapless::Vec;
+
+const TCCR2A_WGM_CTC: u8 = 0b10;
+const TIMSK2_OCIE2A: u8 = 1 << 1;
+// Prescalers of timer 2 and their clock select bits. With at least 32, every tick takes a whole number of µs
+const PRESCALERS: [(u32, u8); 5] = [(32, 0b011), (64, 0b100), (128, 0b101), (256, 0b110), (1024, 0b111)];
+const TICKS_PER_MICRO: u32 = 16;
+
+/// The pins being streamed and the frames their samples go into
+struct Stream {
+    pins: Vec<(Port, u8), MAX_PINS>,
+    filling: SampleFrame,
+    ready: Option<SampleFrame>,
+    tccr2a: u8,
+    tccr2b: u8,
+    ocr2a: u8,
+}
+
+impl Stream {
+    fn sample(&mut self) {
+        let levels = Port::ALL.map(ports::read);
+        let start = self.filling.count as usize * self.pins.len();
+        for (offset, &(port, bit)) in self.pins.iter().enumerate() {
+            if levels[ports::index(port)] & 1 << bit != 0 {
+                let index = start + offset;
+                self.filling.bits[index / 8] |= 1 << (index % 8);
+            }
+        }
+        self.filling.count += 1;
+
+        if (self.filling.count as usize + 1) * self.pins.len() > SAMPLE_FRAME_BYTES * 8 {
+            // If the main loop didn't get around to sending the last frame, it's lost. The sequence tells the host
+            self.ready = Some(self.filling);
+            self.filling = empty_frame(self.filling.sequence.wrapping_add(1));
+        }
+    }
+}
+
+fn empty_frame(sequence: u16) -> SampleFrame {
+    SampleFrame {
+        sequence,
+        count: 0,
+        bits: [0; SAMPLE_FRAME_BYTES],
+    }
+}
+
+static STREAM: Mutex<RefCell<Option<Stream>>> = Mutex::new(RefCell::new(None));
+
+// The pwm module owns timer 2, but PWM on its pins is refused while streaming, see `uses`
+fn tc2() -> &'static tc2::RegisterBlock {
+    unsafe { &*TC2::ptr() }
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn TIMER2_COMPA() {
+    interrupt::free(|cs| {
+        if let Some(stream) = STREAM.borrow(cs).borrow_mut().as_mut() {
+            stream.sample();
+        }
+    });
+}
+
+/// Whether `channel` is driven by the timer that times the samples
+pub fn uses(channel: PwmChannel) -> bool {
+    matches!(channel, PwmChannel::Timer2A | PwmChannel::Timer2B)
+}
+
+pub fn is_running() -> bool {
+    interrupt::free(|cs| STREAM.borrow(cs).borrow().is_some())
+}
+
+/// Start sampling `pins` every `period_us`. Returns the period the timer actually runs at, which is rounded down to
+/// what its prescaler allows. That must not be below [`min_sample_period_us`], and none of the PWM channels the timer
+/// drives may be in use
+pub fn start(pins: Vec<(Port, u8), MAX_PINS>, period_us: u16) -> Result<u16, ErrorCode> {
+    if !(MIN_SAMPLE_PERIOD_US..=MAX_SAMPLE_PERIOD_US).contains(&period_us) {
+        return Err(ErrorCode::InvalidArgument);
+    }
+    let ticks = period_us as u32 * TICKS_PER_MICRO;
+    let (divisor, clock_select) = PRESCALERS
+        .into_iter()
+        .find(|&(divisor, _)| ticks / divisor <= 256)
+        .unwrap_or(PRESCALERS[PRESCALERS.len() - 1]);
+    let compare = ticks / divisor;
+    let actual_us = (compare * divisor / TICKS_PER_MICRO) as u16;
+    if actual_us < min_sample_period_us(pins.len() as u32) {
+        return Err(ErrorCode::InvalidArgument);
+    }
+
+    let stream = Stream {
+        pins,
+        filling: empty_frame(0),
+        ready: None,
+        tccr2a: tc2().tccr2a.read().bits(),
+        tccr2b: tc2().tccr2b.read().bits(),
+        ocr2a: tc2().ocr2a.read().bits(),
+    };
+    interrupt::free(|cs| *STREAM.borrow(cs).borrow_mut() = Some(stream));
+
+    // Clear timer on compare match, so OCR2A sets the period
+    tc2().tccr2a.write(|w| unsafe { w.bits(TCCR2A_WGM_CTC) });
+    tc2().ocr2a.write(|w| unsafe { w.bits((compare - 1) as u8) });
+    tc2().tcnt2.write(|w| unsafe { w.bits(0) });
+    tc2().tccr2b.write(|w| unsafe { w.bits(clock_select) });
+    tc2().timsk2.write(|w| unsafe { w.bits(TIMSK2_OCIE2A) });
+    Ok(actual_us)
+}
+
+/// Stop sampling and hand the timer back to the pwm module. Returns the frames that weren't sent yet, oldest first
+pub fn stop() -> [Option<SampleFrame>; 2] {
+    let stream = interrupt::free(|cs| STREAM.borrow(cs).borrow_mut().take());
+    match stream {
+        Some(stream) => {
+            tc2().timsk2.write(|w| unsafe { w.bits(0) });
+            tc2().tccr2a.write(|w| unsafe { w.bits(stream.tccr2a) });
+            tc2().ocr2a.write(|w| unsafe { w.bits(stream.ocr2a) });
+            tc2().tccr2b.write(|w| unsafe { w.bits(stream.tccr2b) });
+            [stream.ready, Some(stream.filling).filter(|frame| frame.count > 0)]
+        }
+        None => [None, None],
+    }
+}
+
+/// The next frame that is full and ready to be sent, if any
+pub fn take_frame() -> Option<SampleFrame> {
+    interrupt::free(|cs| STREAM.borrow(cs).borrow_mut().as_mut()?.ready.take())
+}
@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+
+/// Incremented whenever the wire format changes in a way that older hosts or firmware can't understand
+pub const PROTOCOL_VERSION: u16 = 2;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl Version {
+    /// Parse a version like `"1.2.3"` at compile time, usually from `env!("CARGO_PKG_VERSION")`.
+    /// Anything behind the patch number, like a pre-release tag, is ignored
+    pub const fn parse(version: &str) -> Self {
+        let bytes = version.as_bytes();
+        let mut parts = [0_u8; 3];
+        let mut part = 0;
+        let mut i = 0;
+        while i < bytes.len() && part < parts.len() {
+            match bytes[i] {
+                b'.' => part += 1,
+                digit @ b'0'..=b'9' => parts[part] = parts[part] * 10 + (digit - b'0'),
+                _ => break,
+            }
+            i += 1;
+        }
+        Self {
+            major: parts[0],
+            minor: parts[1],
+            patch: parts[2],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Board {
+    ArduinoUno,
+    ArduinoNano,
+    ArduinoLeonardo,
+    ArduinoMega2560,
+    SparkFunProMicro,
+}
+
+/// Bitmap of optional features a firmware build supports
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// [`crate::Action::AnalogRead`] and [`crate::Action::ConfigureAnalog`]
+    pub const ANALOG_INPUT: Self = Self(1 << 0);
+    /// [`crate::Action::Pwm`] and [`crate::Action::ConfigurePwm`]
+    pub const PWM: Self = Self(1 << 1);
+    /// [`crate::Action::Ports`] and [`crate::Action::OutputAtomic`]
+    pub const ATOMIC_PORTS: Self = Self(1 << 2);
+    /// [`crate::Action::Subscribe`] and [`crate::Action::Unsubscribe`]
+    pub const EVENTS: Self = Self(1 << 3);
+    /// [`crate::Action::Debounce`]
+    pub const DEBOUNCE: Self = Self(1 << 4);
+    /// [`crate::Action::Pulse`] and [`crate::Action::PulseTrain`]
+    pub const PULSE: Self = Self(1 << 5);
+    /// [`crate::Action::Blink`]
+    pub const BLINK: Self = Self(1 << 6);
+    /// [`crate::Action::MeasurePulse`]
+    pub const MEASURE_PULSE: Self = Self(1 << 7);
+    /// [`crate::Action::CountEdges`] and [`crate::Action::ReadCount`]
+    pub const EDGE_COUNTERS: Self = Self(1 << 8);
+    /// [`crate::Action::MeasureFrequency`]
+    pub const FREQUENCY: Self = Self(1 << 9);
+    /// [`crate::Action::Capture`] and [`crate::Action::ReadCapture`]
+    pub const CAPTURE: Self = Self(1 << 10);
+    /// [`crate::Action::Stream`]
+    pub const STREAM: Self = Self(1 << 11);
+    /// [`crate::Action::LoadPattern`], [`crate::Action::AppendPattern`] and [`crate::Action::Play`]
+    pub const PATTERN: Self = Self(1 << 12);
+    /// [`crate::Action::Heartbeat`] and [`crate::Action::SafeState`]
+    pub const FAILSAFE: Self = Self(1 << 13);
+    /// [`crate::Action::PowerOn`], [`crate::Action::ReadPowerOn`] and [`crate::Action::FactoryReset`]
+    pub const POWER_ON: Self = Self(1 << 14);
+    /// [`crate::Action::Label`], [`crate::Action::Unlabel`] and [`crate::Action::Relabel`]
+    pub const LABELS: Self = Self(1 << 15);
+    /// [`crate::Action::Identify`] and [`crate::Action::SetName`]
+    pub const IDENTITY: Self = Self(1 << 16);
+    /// [`crate::Action::ListPins`]
+    pub const PIN_INFO: Self = Self(1 << 17);
+    /// [`crate::Action::Query`]
+    pub const QUERY: Self = Self(1 << 18);
+    /// [`crate::Action::SetMode`]
+    pub const SET_MODE: Self = Self(1 << 19);
+    /// [`crate::Action::InputMany`] and [`crate::Action::OutputMany`]
+    pub const MANY_PINS: Self = Self(1 << 20);
+    /// [`crate::Action::SetId`]
+    pub const SET_ID: Self = Self(1 << 21);
+    /// [`crate::Action::ListPorts`]
+    pub const LIST_PORTS: Self = Self(1 << 22);
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// What the firmware tells the host about itself in response to [`crate::Action::Hello`].
+///
+/// Fields may only ever be appended, so hosts can always find out which protocol version the firmware speaks.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub protocol_version: u16,
+    pub firmware_version: Version,
+    pub board: Board,
+    pub pin_count: u8,
+    pub capabilities: Capabilities,
+}
+
+impl DeviceInfo {
+    /// Whether the firmware speaks the same protocol as this build of the crate
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+}
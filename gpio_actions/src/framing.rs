@@ -0,0 +1,179 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Terminates every frame on the wire. COBS encoding guarantees this byte never appears inside a frame
+pub const FRAME_DELIMITER: u8 = 0x00;
+
+const CRC_SIZE: usize = 2;
+
+/// Number of bytes a frame adds to the serialized message: one COBS code byte, the CRC-16 and the delimiter
+pub const FRAME_OVERHEAD: usize = 1 + CRC_SIZE + 1;
+
+// COBS needs another code byte for every 254 bytes of data. Our messages are much shorter than that, so we only
+// support a single block, which allows encoding and decoding in place.
+const MAX_FRAME_DATA_SIZE: usize = 253;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame is larger than the buffer it should be written to or read into
+    Overflow,
+    /// The frame is not valid COBS or too short to contain a checksum
+    Malformed,
+    /// The checksum doesn't match the content of the frame
+    Checksum,
+    /// The frame is intact, but its content can't be deserialized
+    Deserialize,
+}
+
+/// CRC-16/CCITT-FALSE. Computed bitwise instead of with a lookup table to save program memory on the firmware
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Serialize `message` into `buffer` as a complete frame, including the trailing [`FRAME_DELIMITER`]
+pub fn to_frame<'a, T>(message: &T, buffer: &'a mut [u8]) -> Result<&'a [u8], FrameError>
+where
+    T: Serialize,
+{
+    // The delimiter goes right behind the data, so it can end one byte before the buffer does
+    let max_data_end = buffer.len().saturating_sub(1).min(1 + MAX_FRAME_DATA_SIZE);
+    if max_data_end < 1 + CRC_SIZE {
+        return Err(FrameError::Overflow);
+    }
+
+    // Leave room for the COBS code byte in front and the CRC behind the message
+    let serialized_len = postcard::to_slice(message, &mut buffer[1..max_data_end - CRC_SIZE])
+        .map_err(|_| FrameError::Overflow)?
+        .len();
+    let crc_start = 1 + serialized_len;
+    let data_end = crc_start + CRC_SIZE;
+    let crc = crc16(&buffer[1..crc_start]);
+    buffer[crc_start..data_end].copy_from_slice(&crc.to_le_bytes());
+
+    // Replace every zero with the distance to the next one, starting from the code byte in front
+    let mut code_index = 0;
+    for index in 1..data_end {
+        if buffer[index] == FRAME_DELIMITER {
+            buffer[code_index] = (index - code_index) as u8;
+            code_index = index;
+        }
+    }
+    buffer[code_index] = (data_end - code_index) as u8;
+    buffer[data_end] = FRAME_DELIMITER;
+    Ok(&buffer[..=data_end])
+}
+
+/// Decode a frame that was received without its trailing [`FRAME_DELIMITER`] in place, verify its checksum and
+/// deserialize the message it contains
+pub fn from_frame<T>(frame: &mut [u8]) -> Result<T, FrameError>
+where
+    T: DeserializeOwned,
+{
+    // Follow the chain of distances and turn every code byte back into the zero it replaced
+    let mut code_index = 0;
+    while code_index < frame.len() {
+        let next_code_index = code_index + frame[code_index] as usize;
+        if next_code_index == code_index || next_code_index > frame.len() {
+            return Err(FrameError::Malformed);
+        }
+        frame[code_index] = 0;
+        code_index = next_code_index;
+    }
+
+    if frame.len() < 1 + CRC_SIZE {
+        return Err(FrameError::Malformed);
+    }
+    let (message, crc) = frame[1..].split_at(frame.len() - 1 - CRC_SIZE);
+    if crc16(message).to_le_bytes() != crc {
+        return Err(FrameError::Checksum);
+    }
+    postcard::from_bytes(message).map_err(|_| FrameError::Deserialize)
+}
+
+/// Collects received bytes until a frame is complete.
+///
+/// After an error, reading simply starts over behind the next [`FRAME_DELIMITER`], so a corrupted or lost byte
+/// costs exactly one frame instead of desynchronizing the whole stream.
+pub struct FrameReader<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+    overflowed: bool,
+}
+
+impl<const N: usize> FrameReader<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    /// Feed the next received byte. Once a frame is complete, returns its message or the reason it was rejected
+    pub fn push<T>(&mut self, byte: u8) -> Option<Result<T, FrameError>>
+    where
+        T: DeserializeOwned,
+    {
+        if byte != FRAME_DELIMITER {
+            if self.len < N {
+                self.buffer[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflowed = true;
+            }
+            return None;
+        }
+
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.overflowed) {
+            return Some(Err(FrameError::Overflow));
+        }
+        if len == 0 {
+            // Empty frames carry no message, they can be used to flush the line
+            return None;
+        }
+        Some(from_frame(&mut self.buffer[..len]))
+    }
+}
+
+impl<const N: usize> Default for FrameReader<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crc16_check_value() {
+        //! Check value from the CRC-16/CCITT-FALSE specification
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+
+    #[test]
+    fn frame_contains_no_delimiter() {
+        //! Zeros in the message must be encoded away, otherwise the receiver would split the frame
+        let message: [u8; 4] = [0, 1, 0, 0];
+        let mut buffer = [0xff_u8; 16];
+        let frame = to_frame(&message, &mut buffer).unwrap();
+        let (delimiter, content) = frame.split_last().unwrap();
+        assert_eq!(*delimiter, FRAME_DELIMITER);
+        assert!(!content.contains(&FRAME_DELIMITER));
+
+        let mut received = [0_u8; 16];
+        received[..content.len()].copy_from_slice(content);
+        assert_eq!(from_frame::<[u8; 4]>(&mut received[..content.len()]), Ok(message));
+    }
+}
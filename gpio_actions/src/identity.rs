@@ -0,0 +1,88 @@
+use core::fmt;
+use serde::{Deserialize, Serialize};
+
+/// Tells a board apart from all others for good. The firmware generates it randomly the first time it starts, in the
+/// format of a version 4 UUID, unless a host sets one with [`crate::Action::SetId`]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DeviceId(pub [u8; 16]);
+
+impl DeviceId {
+    /// Read an ID in the format it's displayed in. The hyphens are optional, but all 32 hex digits have to be there
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut id = Self::default();
+        let mut digits = text.chars().filter(|&c| c != '-');
+        for byte in id.0.iter_mut() {
+            let high = digits.next()?.to_digit(16)?;
+            let low = digits.next()?.to_digit(16)?;
+            *byte = (high << 4 | low) as u8;
+        }
+        match digits.next() {
+            Some(_) => None,
+            None => Some(id),
+        }
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Longest name a [`DeviceName`] can hold, in bytes
+pub const MAX_DEVICE_NAME_LEN: usize = 16;
+
+/// A name users give a board so they can tell it apart from others, in UTF-8
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct DeviceName([u8; MAX_DEVICE_NAME_LEN]); // Padded with zeros, like PinName
+
+impl DeviceName {
+    /// `None` if `name` is longer than [`MAX_DEVICE_NAME_LEN`] bytes or contains a zero
+    pub fn new(name: &str) -> Option<Self> {
+        if name.len() > MAX_DEVICE_NAME_LEN || name.contains('\0') {
+            return None;
+        }
+        let mut device_name = Self::default();
+        device_name.0[..name.len()].copy_from_slice(name.as_bytes());
+        Some(device_name)
+    }
+
+    /// The name without its padding. Bytes that aren't valid UTF-8 are cut off
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|&byte| byte == 0).unwrap_or(MAX_DEVICE_NAME_LEN);
+        match core::str::from_utf8(&self.0[..len]) {
+            Ok(name) => name,
+            Err(error) => core::str::from_utf8(&self.0[..error.valid_up_to()]).unwrap_or_default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0[0] == 0
+    }
+}
+
+impl fmt::Debug for DeviceName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for DeviceName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Who a board is, sent in response to [`crate::Action::Identify`]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Identity {
+    pub id: DeviceId,
+    /// Empty until a name was set with [`crate::Action::SetName`]
+    pub name: DeviceName,
+}
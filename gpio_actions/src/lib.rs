@@ -10,6 +10,18 @@ mod buffered_iterator;
 pub use buffered_iterator::BufferedIterator;
 pub use buffered_iterator::TryFromIter;
 
+mod device_info;
+pub use device_info::{Board, Capabilities, DeviceInfo, Version, PROTOCOL_VERSION};
+
+mod identity;
+pub use identity::{DeviceId, DeviceName, Identity, MAX_DEVICE_NAME_LEN};
+
+mod framing;
+pub use framing::{crc16, from_frame, to_frame, FrameError, FrameReader, FRAME_DELIMITER, FRAME_OVERHEAD};
+
+mod transition;
+pub use transition::{transition, PinBits, PinCall};
+
 use core::fmt::Debug;
 use serde::{Deserialize, Serialize};
 
@@ -22,11 +34,468 @@ pub enum PinState {
     High,
 }
 
+impl core::ops::Not for PinState {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        match self {
+            PinState::Low => PinState::High,
+            PinState::High => PinState::Low,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Default, Clone, Copy)]
+pub enum PinMode {
+    /// Input without pull-up, for sources that drive the line on their own
+    Floating,
+    /// Input with the internal pull-up enabled. Every pin starts out in this mode
+    #[default]
+    PullUp,
+    /// Push-pull output
+    Output,
+    /// Output that only ever pulls the line low and releases it for high. Emulated by switching between a low output
+    /// and a floating input
+    OpenDrain,
+    /// Output driven by a hardware timer, see [`Action::Pwm`]. Only available on some pins
+    Pwm,
+}
+
+impl PinMode {
+    /// The mode a pin is in after [`Action::Output`]. Open-drain pins stay open-drain, all others become outputs
+    pub fn after_output(self) -> Self {
+        match self {
+            PinMode::OpenDrain => PinMode::OpenDrain,
+            _ => PinMode::Output,
+        }
+    }
+
+    /// Whether the pin drives its line in this mode, at least some of the time
+    pub fn is_output(self) -> bool {
+        matches!(self, PinMode::Output | PinMode::OpenDrain | PinMode::Pwm)
+    }
+
+    /// The mode a pin is in after [`Action::Input`]. Outputs become pull-up inputs, all other modes can be read as is
+    pub fn after_input(self) -> Self {
+        match self {
+            PinMode::Output | PinMode::Pwm => PinMode::PullUp,
+            mode => mode,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Default, Clone, Copy)]
+pub enum AnalogReference {
+    /// The supply voltage of the analog circuitry, usually the same as the board's supply voltage
+    #[default]
+    AVcc,
+    /// The internal 1.1V bandgap reference
+    Internal1V1,
+    /// Whatever voltage is applied to the AREF pin
+    External,
+}
+
+/// How [`Action::AnalogRead`] measures. Applies to all analog pins, as they share a single ADC
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct AnalogConfig {
+    pub reference: AnalogReference,
+    /// Number of conversions that are averaged into a single reading. Zero is rejected with
+    /// [`ErrorCode::InvalidArgument`]
+    pub samples: u8,
+}
+
+impl Default for AnalogConfig {
+    fn default() -> Self {
+        Self {
+            reference: AnalogReference::default(),
+            samples: 1,
+        }
+    }
+}
+
+/// Largest value [`Response::Analog`] can contain, corresponding to the reference voltage
+pub const ANALOG_MAX: u16 = 1023;
+
+/// Divides the CPU clock for the timer generating a PWM signal. The PWM frequency is the divided clock / 256.
+/// Not every timer supports every prescaler, the others are rejected with [`ErrorCode::InvalidArgument`]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Default, Clone, Copy)]
+pub enum PwmPrescaler {
+    Direct,
+    Prescale8,
+    Prescale32,
+    #[default]
+    Prescale64,
+    Prescale128,
+    Prescale256,
+    Prescale1024,
+}
+
+impl PwmPrescaler {
+    pub fn divisor(self) -> u16 {
+        match self {
+            PwmPrescaler::Direct => 1,
+            PwmPrescaler::Prescale8 => 8,
+            PwmPrescaler::Prescale32 => 32,
+            PwmPrescaler::Prescale64 => 64,
+            PwmPrescaler::Prescale128 => 128,
+            PwmPrescaler::Prescale256 => 256,
+            PwmPrescaler::Prescale1024 => 1024,
+        }
+    }
+}
+
+/// Bitmap of what a pin can do, see [`PinInfo`]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Default, Clone, Copy)]
+pub struct PinCapabilities(pub u8);
+
+impl PinCapabilities {
+    pub const NONE: Self = Self(0);
+    /// [`Action::Output`], [`Action::Input`] and every mode but [`PinMode::Pwm`]
+    pub const DIGITAL: Self = Self(1 << 0);
+    /// [`Action::AnalogRead`]
+    pub const ANALOG: Self = Self(1 << 1);
+    /// [`Action::Pwm`]
+    pub const PWM: Self = Self(1 << 2);
+    /// [`Action::Subscribe`], [`Action::CountEdges`] and [`Action::Capture`]
+    pub const INTERRUPT: Self = Self(1 << 3);
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// A pin as described by [`Action::ListPins`]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PinInfo {
+    /// Position of the pin in the list, which is also its bit in a [`PinMask`]
+    pub index: u8,
+    pub label: PinLabel,
+    pub name: PinName,
+    pub mode: PinMode,
+    pub capabilities: PinCapabilities,
+}
+
+/// Set of pins, where bit `n` stands for the `n`th pin in the order [`Action::List`] lists them.
+/// Only the first 32 pins can be addressed like this
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Default, Clone, Copy)]
+pub struct PinMask(pub u32);
+
+impl PinMask {
+    pub const NONE: Self = Self(0);
+    pub const ALL: Self = Self(u32::MAX);
+
+    /// The mask containing only the pin at `index`. Empty if `index` can't be addressed
+    pub const fn single(index: usize) -> Self {
+        if index < u32::BITS as usize {
+            Self(1 << index)
+        } else {
+            Self::NONE
+        }
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn contains(self, index: usize) -> bool {
+        self.0 & Self::single(index).0 != 0
+    }
+}
+
+/// An I/O port of the microcontroller. All pins on the same port can be written at once, see
+/// [`Action::OutputAtomic`]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Port {
+    B,
+    C,
+    D,
+}
+
+impl Port {
+    pub const ALL: [Port; 3] = [Port::B, Port::C, Port::D];
+}
+
+/// Which level changes of a pin cause a [`Response::Event`]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Default, Clone, Copy)]
+pub enum Edge {
+    Rising,
+    Falling,
+    #[default]
+    Both,
+}
+
+impl Edge {
+    /// Whether a pin changing to `state` is such an edge
+    pub fn matches(self, state: PinState) -> bool {
+        match self {
+            Edge::Rising => state == PinState::High,
+            Edge::Falling => state == PinState::Low,
+            Edge::Both => true,
+        }
+    }
+}
+
+/// How a pin filters out contact bounce before a level change counts, see [`Action::Debounce`]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Default, Clone, Copy)]
+pub enum Debounce {
+    #[default]
+    Off,
+    /// The new level has to stay stable for this many milliseconds
+    Millis(u16),
+    /// The new level has to be read this many times in a row. The firmware reads as fast as it can
+    Samples(u8),
+}
+
+/// A series of pulses timed by the firmware, see [`Action::PulseTrain`]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PulseTrain {
+    /// The level of each pulse. The pin returns to the opposite level in between
+    pub state: PinState,
+    pub width_us: u32,
+    pub count: u16,
+    /// Time from the start of one pulse to the start of the next, must be longer than `width_us`
+    pub period_us: u32,
+}
+
+impl PulseTrain {
+    /// How long it takes from the start of the first pulse to the end of the last one, in microseconds
+    pub fn duration_us(&self) -> u64 {
+        match self.count {
+            0 => 0,
+            count => (count as u64 - 1) * self.period_us as u64 + self.width_us as u64,
+        }
+    }
+}
+
+/// Longest an action may keep the firmware from reading other actions, in microseconds. Applies to the duration of
+/// [`Action::Pulse`] and [`Action::PulseTrain`], the timeout of [`Action::MeasurePulse`] and the gate time of
+/// [`Action::MeasureFrequency`], which are rejected with [`ErrorCode::InvalidArgument`] above it
+pub const MAX_BLOCKING_US: u32 = 10_000_000;
+
+/// Longest gate time [`Action::MeasureFrequency`] accepts, in milliseconds, see [`MAX_BLOCKING_US`]
+pub const MAX_GATE_MS: u16 = (MAX_BLOCKING_US / 1000) as u16;
+
+/// A square wave the firmware keeps generating on its own, see [`Action::Blink`]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Blink {
+    pub period_ms: u16,
+    /// Percentage of each period the pin is high for, starting with the high part. It has to come out as at least one
+    /// millisecond high and low, unless it's 0 or 100
+    pub duty: u8,
+    /// Number of periods until the pin stays low, or `None` to blink until told otherwise
+    pub count: Option<u16>,
+}
+
+/// Most edges [`Action::Capture`] can record at once
+pub const MAX_CAPTURE_EDGES: u16 = 64;
+
+/// Number of edges sent in each [`CaptureChunk`]
+pub const CAPTURE_CHUNK_LEN: usize = 3;
+
+/// Part of the edges recorded by [`Action::Capture`]. All chunks of one capture are sent in a row
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct CaptureChunk {
+    /// Index of the first edge in this chunk
+    pub offset: u16,
+    /// Number of edges recorded in total
+    pub total: u16,
+    /// When each edge happened, in microseconds since the capture started. Entries past `total` are unused
+    pub micros: [u32; CAPTURE_CHUNK_LEN],
+    /// The level each edge changed to, one bit per entry
+    pub levels: u8,
+}
+
+impl CaptureChunk {
+    /// The time and new level of each edge in this chunk
+    pub fn edges(&self) -> impl Iterator<Item = (u32, PinState)> + '_ {
+        let len = (self.total.saturating_sub(self.offset) as usize).min(CAPTURE_CHUNK_LEN);
+        self.micros[..len].iter().enumerate().map(|(index, &micros)| {
+            let state = if self.levels & 1 << index != 0 {
+                PinState::High
+            } else {
+                PinState::Low
+            };
+            (micros, state)
+        })
+    }
+
+    /// Whether no more chunks follow this one
+    pub fn is_last(&self) -> bool {
+        self.offset as usize + CAPTURE_CHUNK_LEN >= self.total as usize
+    }
+}
+
+/// Shortest period [`Action::Stream`] can sample at, in microseconds
+pub const MIN_SAMPLE_PERIOD_US: u16 = 100;
+
+/// Longest period [`Action::Stream`] can sample at, in microseconds
+pub const MAX_SAMPLE_PERIOD_US: u16 = 16384;
+
+/// Number of bytes of samples in each [`SampleFrame`]
+pub const SAMPLE_FRAME_BYTES: usize = 16;
+
+/// Baud rate of the serial link between host and firmware
+pub const BAUD_RATE: u32 = 57600;
+
+/// Largest a framed [`Response::Samples`] gets, in bytes: one byte each for the ID, the variant and the count, up to
+/// three for the sequence, and the samples themselves
+const SAMPLE_FRAME_SIZE: u32 = 1 + 1 + 3 + 1 + SAMPLE_FRAME_BYTES as u32 + FRAME_OVERHEAD as u32;
+
+/// Shortest period [`Action::Stream`] accepts for `pin_count` pins, in microseconds. Any shorter and the samples would
+/// pile up faster than the serial link can send them
+pub fn min_sample_period_us(pin_count: u32) -> u16 {
+    // Every byte takes ten bits on the wire, counting the start and stop bits
+    let frame_us = (SAMPLE_FRAME_SIZE * 10 * 1_000_000 - 1) / BAUD_RATE + 1;
+    let samples = SAMPLE_FRAME_BYTES as u32 * 8 / pin_count.max(1);
+    // Rounded up, like the frame
+    let period_us = (frame_us - 1) / samples + 1;
+    period_us.clamp(MIN_SAMPLE_PERIOD_US as u32, MAX_SAMPLE_PERIOD_US as u32) as u16
+}
+
+/// Consecutive samples of the pins streamed by [`Action::Stream`]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SampleFrame {
+    /// Counts up by one for every frame, so hosts notice when one got lost
+    pub sequence: u16,
+    /// Number of samples in this frame
+    pub count: u8,
+    /// One bit per streamed pin and sample, in the order of the pin mask. Samples follow each other without gaps
+    pub bits: [u8; SAMPLE_FRAME_BYTES],
+}
+
+impl SampleFrame {
+    /// The levels of `pins` in each sample, like the levels of [`Response::InputMany`]. `pins` has to be the mask from
+    /// [`Response::Streaming`]
+    pub fn samples(&self, pins: PinMask) -> impl Iterator<Item = PinMask> + '_ {
+        let pin_count = pins.0.count_ones() as usize;
+        (0..self.count as usize).map(move |sample| {
+            let indices = (0..32).filter(|&index| pins.contains(index));
+            indices.enumerate().fold(PinMask::NONE, |levels, (offset, index)| {
+                let bit = sample * pin_count + offset;
+                if self.bits[bit / 8] & 1 << (bit % 8) != 0 {
+                    levels.union(PinMask::single(index))
+                } else {
+                    levels
+                }
+            })
+        })
+    }
+}
+
+/// Most steps a pattern for [`Action::Play`] can have
+pub const MAX_PATTERN_STEPS: u16 = 64;
+
+/// Shortest tick [`Action::LoadPattern`] accepts, in microseconds
+pub const MIN_PATTERN_TICK_US: u32 = 20;
+
+/// Longest tick [`Action::LoadPattern`] accepts, in microseconds
+pub const MAX_PATTERN_TICK_US: u32 = 4_194_304;
+
+/// Number of steps sent in each [`PatternSteps`]
+pub const PATTERN_STEPS_LEN: usize = 3;
+
+/// Steps appended to the pattern by [`Action::AppendPattern`]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PatternSteps {
+    /// Number of entries of `levels` that are used
+    pub count: u8,
+    /// The levels of the pattern's pins in each step, like the levels of [`Action::OutputMany`]
+    pub levels: [PinMask; PATTERN_STEPS_LEN],
+}
+
+/// How a loaded pattern is played back, see [`Action::Play`]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Default, Clone, Copy)]
+pub enum Playback {
+    #[default]
+    Stop,
+    Once,
+    /// Starts over after the last step until told to stop
+    Loop,
+}
+
+// New variants are only ever appended, so that Hello keeps working across protocol versions
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Action {
     Output(PinLabel, PinState),
     Input(PinLabel),
     List,
+    Hello,
+    SetMode(PinLabel, PinMode), // Outputs start out at the level the pull-up had, so a pull-up input becomes high
+    AnalogRead(PinLabel),       // Leaves the pin as a floating input, as a pull-up would skew the measurement
+    ConfigureAnalog(AnalogConfig),
+    Pwm(PinLabel, u8),                    // Duty cycle from 0 (always low) to 255 (always high)
+    ConfigurePwm(PinLabel, PwmPrescaler), // Also affects the other pin driven by the same timer
+    InputMany(PinMask),                   // Like Input for every pin in the mask, all in one go
+    OutputMany(PinMask, PinMask),         // Sets every pin in the first mask to its bit in the second one
+    Ports,                                // Which pins share a port and can be written by OutputAtomic together
+    OutputAtomic(PinMask, PinMask),       // Like OutputMany, but all pins change at the same instant
+    Subscribe(PinLabel, Edge),            // Every matching level change is reported with an Event from then on
+    Unsubscribe(PinLabel),
+    Debounce(PinLabel, Debounce), // Filters what Input, InputMany and Event report for the pin
+    // Drives the pin to the state for as many microseconds, then to the opposite state. Like Output, this turns the
+    // pin into an output. No other action is read until the response was sent, so hosts should wait for it. At most
+    // MAX_BLOCKING_US long
+    Pulse(PinLabel, PinState, u32),
+    PulseTrain(PinLabel, PulseTrain), // Like Pulse, but repeated. All pulses together must fit into MAX_BLOCKING_US
+    // Keeps going until any other action changes the pin's level or mode. Timed by an interrupt, so actions that keep
+    // the firmware busy don't hold it up
+    Blink(PinLabel, Blink),
+    // Waits for the pin to change to the state and measures how long it stays there, like pulseIn on the Arduino
+    // core. Gives up after the timeout in microseconds, at most MAX_BLOCKING_US. Like Pulse, no other action is read
+    // in the meantime
+    MeasurePulse(PinLabel, PinState, u32),
+    CountEdges(PinLabel, Option<Edge>), // Starts counting matching edges from zero, or stops counting on None
+    ReadCount(PinLabel, bool),          // If the flag is set, the count starts over at zero after reading it
+    // Counts rising edges for the gate time in milliseconds, at most MAX_GATE_MS. Only works on the pin that can clock
+    // a timer. Like Pulse, no other action is read in the meantime
+    MeasureFrequency(PinLabel, u16),
+    // Records the time of up to this many level changes in the background, or stops on 0. Only one pin at a time
+    Capture(PinLabel, u16),
+    ReadCapture(PinLabel), // Sends the edges recorded so far, the capture keeps going until it's full
+    // Samples the pins in the mask every so many microseconds and sends them in Samples until the mask is empty.
+    // The more pins, the longer the period has to be for the samples to fit through the serial link, see
+    // min_sample_period_us. PWM on the pins driven by timer 2 isn't available in the meantime
+    Stream(PinMask, u16),
+    // Empties the pattern and sets the pins it drives and the time each step lasts in microseconds
+    LoadPattern(PinMask, u32),
+    AppendPattern(PatternSteps),
+    // Plays the pattern in the background, stopping any earlier playback. All of its pins have to be outputs. Like
+    // Stream, this takes over a timer, so PWM on the pins driven by timer 1 isn't available in the meantime
+    Play(Playback),
+    // Puts every output into its safe state if no frame arrives for this many milliseconds, or never on 0. Any frame
+    // counts, so hosts that are idle otherwise should repeat this action well within the timeout
+    Heartbeat(u16),
+    SafeState(PinLabel, Option<PinState>), // The level the failsafe drives the output to, or None for an input
+    // Stores the mode and level the pin is put into at every reset, before any action is read. The level only matters
+    // for outputs, PullUp removes the default and Pwm isn't available. Takes effect after the next reset
+    PowerOn(PinLabel, PinMode, PinState),
+    ReadPowerOn(PinLabel),
+    // Forgets every setting stored in EEPROM, including the name but not the ID from Identify. Like PowerOn, this only
+    // takes effect after the next reset
+    FactoryReset,
+    // Gives the pin with the name from List the label, replacing the one it had. No other pin may have the label yet.
+    // Labels are stored in EEPROM like PowerOn, but take effect right away. Masks follow the new order of List
+    Label(PinLabel, PinName),
+    Unlabel(PinLabel), // The pin keeps its mode and level, but can't be used until it gets a label again
+    Relabel(PinLabel, PinLabel),
+    Identify,
+    SetName(DeviceName), // Stored in EEPROM like PowerOn, but takes effect right away
+    ListPins,            // Like List, but says how many pins follow and describes each of them
+    // Like Input, but never changes the pin's mode. Outputs report the level they drive, everything else the level
+    // Input would report
+    Query(PinLabel),
+    // Replaces the ID from Identify, for hosts that keep their own inventory or boards whose ID turned out not to be
+    // unique. Stored in EEPROM right away and kept by FactoryReset, like the ID the firmware generates
+    SetId(DeviceId),
+    ListPorts, // Like Ports, but says how many ports follow
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
@@ -34,17 +503,116 @@ pub enum Response {
     Output(PinLabel, PinState),
     Input(PinLabel, PinState),
     List(PinLabel, PinName), // This response is sent once for every pin
-    Err,
+    Err(ErrorCode, Option<PinLabel>),
+    Hello(DeviceInfo),
+    Mode(PinLabel, PinMode),
+    Analog(PinLabel, u16),
+    AnalogConfig(AnalogConfig),
+    Pwm(PinLabel, u8),
+    PwmConfig(PinLabel, PwmPrescaler),
+    InputMany(PinMask, PinMask), // The pins that exist and were read, and their levels
+    OutputMany(PinMask, PinMask),
+    Port(Port, PinMask), // This response is sent once for every port that has pins
+    OutputAtomic(PinMask, PinMask),
+    Subscribed(PinLabel, Edge),
+    Unsubscribed(PinLabel),
+    Event(PinLabel, PinState), // Sent on its own with NO_TRANSACTION, the state is the level the pin changed to
+    Debounce(PinLabel, Debounce),
+    Pulse(PinLabel, PinState, u32), // Sent once the pulse is over
+    PulseTrain(PinLabel, PulseTrain),
+    Blink(PinLabel, Blink),              // Sent right away, not after the last period
+    PulseWidth(PinLabel, PinState, u32), // In microseconds, with a resolution of 4µs
+    CountingEdges(PinLabel, Option<Edge>),
+    Count(PinLabel, u32, bool), // The flag is set if the count overflowed since it was last reset
+    Frequency(PinLabel, u32),   // In Hz
+    Capturing(PinLabel, u16),
+    Capture(PinLabel, CaptureChunk), // This response is sent once for every chunk, there's always at least one
+    Streaming(PinMask, u16),         // The pins that are actually sampled, and the period the timer could achieve
+    Samples(SampleFrame),            // Sent on its own with NO_TRANSACTION, like Event
+    PatternLoaded(PinMask, u32),     // The pins that are actually driven, and the tick the timer could achieve
+    PatternLength(u16),
+    Playing(Playback), // Sent right away
+    // Sent with the ID of the Play action once the playback is over or was stopped, with the number of steps played
+    PlaybackDone(u32),
+    Heartbeat(u16),
+    SafeState(PinLabel, Option<PinState>),
+    // Sent on its own with NO_TRANSACTION before the response to the first frame after the failsafe tripped, with the
+    // number of milliseconds since then. The modes of all outputs may have changed in the meantime
+    FailsafeTripped(u32),
+    PowerOn(PinLabel, PinMode, PinState), // The level is always Low for inputs
+    FactoryReset,
+    Labeled(PinLabel, PinName),
+    Unlabeled(PinLabel, PinName),
+    Relabeled(PinLabel, PinLabel),
+    Identity(Identity),
+    PinCount(u8), // Sent before the responses to ListPins, so hosts know when they have all of them
+    Pin(PinInfo), // This response is sent once for every pin, in the order of List
+    Query(PinLabel, PinMode, PinState),
+    PortCount(u8), // Sent before the responses to ListPorts, so hosts know when they have all of them
+}
+
+/// Why an [`Action`] couldn't be executed, sent in [`Response::Err`] together with the offending pin, if any.
+///
+/// Errors about whole frames are sent with [`NO_TRANSACTION`], as the ID inside the frame can't be read.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// No pin with this label exists
+    UnknownPin,
+    /// The pin doesn't support the requested mode
+    InvalidMode,
+    /// The frame was corrupted or incomplete
+    MalformedFrame,
+    /// The frame was intact, but the firmware doesn't know the action it contained
+    UnsupportedAction,
+    /// The firmware is still occupied with an earlier action
+    Busy,
+    /// The pins are spread over several ports, so they can't be written at once
+    PortMismatch,
+    /// A parameter of the action is out of range
+    InvalidArgument,
+    /// What the action waited for didn't happen in time
+    Timeout,
+    /// The host stopped sending while the action was running, so it was cut short for the failsafe to trip
+    Aborted,
 }
 
-/// Maximum size a serialized [`Action`] can have on the wire, in bytes
-pub const MAX_ACTION_WIRE_SIZE: usize = 8;
+impl From<FrameError> for ErrorCode {
+    fn from(error: FrameError) -> Self {
+        match error {
+            FrameError::Deserialize => ErrorCode::UnsupportedAction,
+            FrameError::Overflow | FrameError::Malformed | FrameError::Checksum => ErrorCode::MalformedFrame,
+        }
+    }
+}
+
+/// Identifies an [`Action`] so the [`Response`]s it causes can be matched to it. The firmware echoes it back unchanged
+pub type TransactionId = u16;
+
+/// Reserved for responses that don't belong to any [`Action`]. Hosts never use it for their own actions
+pub const NO_TRANSACTION: TransactionId = 0;
+
+/// Wraps an [`Action`] or [`Response`] together with the [`TransactionId`] it belongs to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Envelope<T> {
+    pub id: TransactionId,
+    pub payload: T,
+}
+
+/// Maximum size a serialized [`Action`] can have on the wire including its [`Envelope`], in bytes
+pub const MAX_ACTION_WIRE_SIZE: usize = 24;
+
+/// Maximum size a serialized [`Response`] can have on the wire including its [`Envelope`], in bytes
+pub const MAX_RESPONSE_WIRE_SIZE: usize = 40;
+
+/// Size of the buffer needed to send or receive a framed [`Action`], in bytes
+pub const MAX_ACTION_FRAME_SIZE: usize = MAX_ACTION_WIRE_SIZE + FRAME_OVERHEAD;
 
-/// Maximum size a serialized [`Response`] can have on the wire, in bytes
-pub const MAX_RESPONSE_WIRE_SIZE: usize = 16;
+/// Size of the buffer needed to send or receive a framed [`Response`], in bytes
+pub const MAX_RESPONSE_FRAME_SIZE: usize = MAX_RESPONSE_WIRE_SIZE + FRAME_OVERHEAD;
 
 #[cfg(test)]
 mod test {
+    use core::str::FromStr;
     use heapless::Vec;
 
     use super::*;
@@ -67,4 +635,268 @@ mod test {
         let deserialized = Action::try_from_iter::<MAX_ACTION_WIRE_SIZE>(&mut serialized.into_iter()).unwrap();
         assert_eq!(action, deserialized);
     }
+
+    #[test]
+    fn envelope_fits_wire_size() {
+        //! The largest transaction ID and a multi-byte label must still fit into the advertised wire size
+        let envelope = Envelope {
+            id: TransactionId::MAX,
+            payload: Action::Output('\u{1F4A1}', PinState::High),
+        };
+        let serialized: Vec<u8, MAX_ACTION_WIRE_SIZE> = postcard::to_vec(&envelope).unwrap();
+        let deserialized =
+            Envelope::<Action>::try_from_iter::<MAX_ACTION_WIRE_SIZE>(&mut serialized.into_iter()).unwrap();
+        assert_eq!(envelope, deserialized);
+
+        let envelope = Envelope {
+            id: TransactionId::MAX,
+            payload: Action::PulseTrain(
+                '\u{1F4A1}',
+                PulseTrain {
+                    state: PinState::High,
+                    width_us: u32::MAX,
+                    count: u16::MAX,
+                    period_us: u32::MAX,
+                },
+            ),
+        };
+        let _: Vec<u8, MAX_ACTION_WIRE_SIZE> = postcard::to_vec(&envelope).unwrap();
+
+        let envelope = Envelope {
+            id: TransactionId::MAX,
+            payload: Action::AppendPattern(PatternSteps {
+                count: u8::MAX,
+                levels: [PinMask::ALL; PATTERN_STEPS_LEN],
+            }),
+        };
+        let _: Vec<u8, MAX_ACTION_WIRE_SIZE> = postcard::to_vec(&envelope).unwrap();
+
+        let envelope = Envelope {
+            id: TransactionId::MAX,
+            payload: Response::Hello(DeviceInfo {
+                protocol_version: u16::MAX,
+                firmware_version: Version::parse("255.255.255"),
+                board: Board::SparkFunProMicro,
+                pin_count: u8::MAX,
+                capabilities: Capabilities(u32::MAX),
+            }),
+        };
+        let _: Vec<u8, MAX_RESPONSE_WIRE_SIZE> = postcard::to_vec(&envelope).unwrap();
+
+        let envelope = Envelope {
+            id: TransactionId::MAX,
+            payload: Response::Capture(
+                '\u{1F4A1}',
+                CaptureChunk {
+                    offset: u16::MAX,
+                    total: u16::MAX,
+                    micros: [u32::MAX; CAPTURE_CHUNK_LEN],
+                    levels: u8::MAX,
+                },
+            ),
+        };
+        let _: Vec<u8, MAX_RESPONSE_WIRE_SIZE> = postcard::to_vec(&envelope).unwrap();
+
+        let envelope = Envelope {
+            id: TransactionId::MAX,
+            payload: Response::Pin(PinInfo {
+                index: u8::MAX,
+                label: '\u{1F4A1}',
+                name: PinName::from_str("D66").unwrap(),
+                mode: PinMode::OpenDrain,
+                capabilities: PinCapabilities(u8::MAX),
+            }),
+        };
+        let _: Vec<u8, MAX_RESPONSE_WIRE_SIZE> = postcard::to_vec(&envelope).unwrap();
+
+        let name = DeviceName::new("\u{1F4A1}\u{1F4A1}\u{1F4A1}\u{1F4A1}").unwrap();
+        let envelope = Envelope {
+            id: TransactionId::MAX,
+            payload: Action::SetName(name),
+        };
+        let _: Vec<u8, MAX_ACTION_WIRE_SIZE> = postcard::to_vec(&envelope).unwrap();
+
+        let envelope = Envelope {
+            id: TransactionId::MAX,
+            payload: Action::SetId(DeviceId([u8::MAX; 16])),
+        };
+        let _: Vec<u8, MAX_ACTION_WIRE_SIZE> = postcard::to_vec(&envelope).unwrap();
+
+        let envelope = Envelope {
+            id: TransactionId::MAX,
+            payload: Response::Identity(Identity {
+                id: DeviceId([u8::MAX; 16]),
+                name,
+            }),
+        };
+        let _: Vec<u8, MAX_RESPONSE_WIRE_SIZE> = postcard::to_vec(&envelope).unwrap();
+    }
+
+    #[test]
+    fn parse_version() {
+        let version = Version {
+            major: 1,
+            minor: 22,
+            patch: 3,
+        };
+        assert_eq!(Version::parse("1.22.3"), version);
+        assert_eq!(Version::parse("1.22.3-alpha.1"), version);
+    }
+
+    #[test]
+    fn implicit_mode_changes() {
+        //! Hosts rely on these to track pin modes without asking the firmware
+        assert_eq!(PinMode::PullUp.after_output(), PinMode::Output);
+        assert_eq!(PinMode::OpenDrain.after_output(), PinMode::OpenDrain);
+        assert_eq!(PinMode::Output.after_input(), PinMode::PullUp);
+        assert_eq!(PinMode::Floating.after_input(), PinMode::Floating);
+    }
+
+    #[test]
+    fn pin_mask_indices() {
+        let mask = PinMask::single(0).union(PinMask::single(31));
+        assert_eq!(mask, PinMask(0x8000_0001));
+        assert!(mask.contains(31));
+        assert!(!mask.contains(1));
+        // Pins beyond the mask can't be addressed, but must not wrap around either
+        assert_eq!(PinMask::single(32), PinMask::NONE);
+        assert!(!PinMask::ALL.contains(32));
+    }
+
+    #[test]
+    fn capture_chunk_edges() {
+        //! Unused entries of the last chunk must not show up as edges
+        let chunk = CaptureChunk {
+            offset: 3,
+            total: 5,
+            micros: [12, 40, 0],
+            levels: 0b10,
+        };
+        let edges: Vec<(u32, PinState), CAPTURE_CHUNK_LEN> = chunk.edges().collect();
+        assert_eq!(edges, [(12, PinState::Low), (40, PinState::High)]);
+        assert!(chunk.is_last());
+        assert!(!CaptureChunk { offset: 0, ..chunk }.is_last());
+    }
+
+    #[test]
+    fn sample_frame_unpacking() {
+        //! Samples are packed without gaps, so they don't line up with bytes
+        let pins = PinMask::single(1).union(PinMask::single(4)).union(PinMask::single(9));
+        let mut bits = [0; SAMPLE_FRAME_BYTES];
+        bits[0] = 0b1010_1010;
+        bits[1] = 0b0000_0001;
+        let frame = SampleFrame {
+            sequence: 0,
+            count: 3,
+            bits,
+        };
+        let samples: Vec<PinMask, 3> = frame.samples(pins).collect();
+        assert_eq!(
+            samples,
+            [
+                PinMask::single(4),
+                PinMask::single(1).union(PinMask::single(9)),
+                PinMask::single(4).union(PinMask::single(9)),
+            ]
+        );
+    }
+
+    #[test]
+    fn sample_periods_fit_the_link() {
+        //! Streaming at the shortest period must not produce more bytes than the link can send
+        let envelope = Envelope {
+            id: NO_TRANSACTION,
+            payload: Response::Samples(SampleFrame {
+                sequence: u16::MAX,
+                count: u8::MAX,
+                bits: [u8::MAX; SAMPLE_FRAME_BYTES],
+            }),
+        };
+        let serialized: Vec<u8, MAX_RESPONSE_WIRE_SIZE> = postcard::to_vec(&envelope).unwrap();
+        assert!(serialized.len() + FRAME_OVERHEAD <= SAMPLE_FRAME_SIZE as usize);
+
+        assert_eq!(min_sample_period_us(1), MIN_SAMPLE_PERIOD_US);
+        for pin_count in 1..=32 {
+            let samples = (SAMPLE_FRAME_BYTES * 8) as u32 / pin_count;
+            let frame_us = min_sample_period_us(pin_count) as u32 * samples;
+            assert!(
+                SAMPLE_FRAME_SIZE * 10 * 1_000_000 <= frame_us * BAUD_RATE,
+                "{} pins",
+                pin_count
+            );
+        }
+    }
+
+    #[test]
+    fn pulse_train_duration() {
+        //! The last pulse isn't followed by a gap, and the largest trains mustn't overflow
+        let train = PulseTrain {
+            state: PinState::High,
+            width_us: 10,
+            count: 3,
+            period_us: 100,
+        };
+        assert_eq!(train.duration_us(), 210);
+        assert_eq!(PulseTrain { count: 0, ..train }.duration_us(), 0);
+        let train = PulseTrain {
+            width_us: u32::MAX,
+            count: u16::MAX,
+            period_us: u32::MAX,
+            ..train
+        };
+        assert_eq!(train.duration_us(), u16::MAX as u64 * u32::MAX as u64);
+    }
+
+    #[test]
+    fn device_identity_formatting() {
+        //! IDs look like UUIDs, names lose their padding and can't be longer than the wire allows
+        use core::fmt::Write;
+        let mut text: heapless::String<64> = heapless::String::new();
+        let id = DeviceId([
+            0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0x4d, 0xef, 0x80, 1, 2, 3, 4, 5, 6, 7,
+        ]);
+        write!(text, "{}", id).unwrap();
+        assert_eq!(text, "12345678-9abc-4def-8001-020304050607");
+
+        assert_eq!(DeviceName::new("bench 3").unwrap().as_str(), "bench 3");
+        assert_eq!(DeviceName::new("").unwrap().as_str(), "");
+        assert!(DeviceName::new("").unwrap().is_empty());
+        assert!(DeviceName::new("sixteen bytes ok").is_some());
+        assert!(DeviceName::new("seventeen bytes!!").is_none());
+        assert!(DeviceName::new("nul\0").is_none());
+
+        assert_eq!(DeviceId::parse("12345678-9abc-4def-8001-020304050607"), Some(id));
+        assert_eq!(DeviceId::parse("123456789ABC4DEF8001020304050607"), Some(id));
+        assert_eq!(DeviceId::parse("12345678-9abc-4def-8001-0203040506"), None);
+        assert_eq!(DeviceId::parse("12345678-9abc-4def-8001-02030405060708"), None);
+        assert_eq!(DeviceId::parse("12345678-9abc-4def-8001-02030405060g"), None);
+    }
+
+    #[test]
+    fn frame_reader_resynchronizes() {
+        //! A corrupted frame must be rejected without affecting the frame behind it
+        let first = Envelope {
+            id: 1,
+            payload: Action::Input('1'),
+        };
+        let second = Envelope {
+            id: 2,
+            payload: Action::List,
+        };
+        let mut first_buffer = [0_u8; MAX_ACTION_FRAME_SIZE];
+        let mut second_buffer = [0_u8; MAX_ACTION_FRAME_SIZE];
+        let mut stream: Vec<u8, { 2 * MAX_ACTION_FRAME_SIZE }> = Vec::new();
+        stream
+            .extend_from_slice(to_frame(&first, &mut first_buffer).unwrap())
+            .unwrap();
+        stream
+            .extend_from_slice(to_frame(&second, &mut second_buffer).unwrap())
+            .unwrap();
+        stream[2] ^= 0x10;
+
+        let mut reader = FrameReader::<MAX_ACTION_FRAME_SIZE>::new();
+        let results: Vec<Result<Envelope<Action>, FrameError>, 2> =
+            stream.into_iter().filter_map(|byte| reader.push(byte)).collect();
+        assert_eq!(results, [Err(FrameError::Checksum), Ok(second)]);
+    }
 }
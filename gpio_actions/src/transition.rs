@@ -0,0 +1,253 @@
+//! How a pin gets from one mode to another without glitches.
+//!
+//! Every AVR pin is controlled by two register bits. Its DDR bit turns the output driver on, and its PORT bit is the
+//! level the driver drives or, while the driver is off, enables the pull-up. Only one of them can be written at a time,
+//! so on the way to an output the level has to be in place before the driver is turned on, and on the way to an input
+//! the driver has to be turned off before the level changes. Otherwise the pin drives the wrong level for a moment,
+//! which is enough to reset a peripheral with an active-low reset line.
+//!
+//! The firmware writes the bits of each step itself, in this order, and then calls the avr-hal pin conversion
+//! [`PinCall`] names to update the pin's type state. The conversion finds its bits in place already, so the order it
+//! writes them in doesn't matter.
+
+use crate::PinState;
+
+/// The DDR and PORT bits of a pin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinBits {
+    /// The DDR bit, set if the output driver is on
+    pub output: bool,
+    /// The PORT bit, set if the pin drives high or, for inputs, if the pull-up is on
+    pub high: bool,
+}
+
+impl PinBits {
+    pub const FLOATING: Self = Self {
+        output: false,
+        high: false,
+    };
+    pub const PULL_UP: Self = Self {
+        output: false,
+        high: true,
+    };
+
+    pub const fn output(state: PinState) -> Self {
+        Self {
+            output: true,
+            high: matches!(state, PinState::High),
+        }
+    }
+
+    /// The conversion that sets a pin's registers to these bits
+    pub const fn call(self) -> PinCall {
+        match self {
+            PinBits {
+                output: false,
+                high: false,
+            } => PinCall::FloatingInput,
+            PinBits {
+                output: false,
+                high: true,
+            } => PinCall::PullUpInput,
+            PinBits {
+                output: true,
+                high: false,
+            } => PinCall::Output,
+            PinBits {
+                output: true,
+                high: true,
+            } => PinCall::OutputHigh,
+        }
+    }
+}
+
+/// One of avr-hal's pin conversions, which work from any mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinCall {
+    /// `into_floating_input`, which clears DDR and PORT
+    FloatingInput,
+    /// `into_pull_up_input`, which clears DDR and sets PORT
+    PullUpInput,
+    /// `into_output`, which clears PORT and sets DDR
+    Output,
+    /// `into_output_high`, which sets PORT and DDR
+    OutputHigh,
+}
+
+/// The bits to write one after another to get from `from` to `to`. Every step changes a single bit, and the pin never
+/// drives a level that neither `from` nor `to` drives
+pub fn transition(from: PinBits, to: PinBits) -> impl Iterator<Item = PinBits> {
+    let intermediate = if from.output && !to.output {
+        PinBits {
+            output: false,
+            high: from.high,
+        }
+    } else {
+        PinBits {
+            output: from.output,
+            high: to.high,
+        }
+    };
+    let mut last = from;
+    [intermediate, to].into_iter().filter(move |&step| {
+        let changes = step != last;
+        last = step;
+        changes
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::vec::Vec;
+
+    const ALL: [PinBits; 4] = [
+        PinBits::FLOATING,
+        PinBits::PULL_UP,
+        PinBits::output(PinState::Low),
+        PinBits::output(PinState::High),
+    ];
+
+    /// The registers of a port, recording which level each of its pins drove after every write. Pins are changed like
+    /// avr-hal does it, so a conversion that writes the bits in the wrong order shows up in the recording
+    struct MockPort {
+        ddr: u8,
+        port: u8,
+        driven: Vec<[Option<PinState>; 8]>,
+    }
+
+    impl MockPort {
+        /// A port with `pins` set up already, and nothing recorded yet
+        fn new(pins: &[(u8, PinBits)]) -> Self {
+            let mut port = Self {
+                ddr: 0,
+                port: 0,
+                driven: Vec::new(),
+            };
+            for &(bit, bits) in pins {
+                port.ddr |= (bits.output as u8) << bit;
+                port.port |= (bits.high as u8) << bit;
+            }
+            port
+        }
+
+        fn write_ddr(&mut self, bit: u8, set: bool) {
+            self.ddr = if set {
+                self.ddr | 1 << bit
+            } else {
+                self.ddr & !(1 << bit)
+            };
+            self.record();
+        }
+
+        fn write_port(&mut self, bit: u8, set: bool) {
+            self.port = if set {
+                self.port | 1 << bit
+            } else {
+                self.port & !(1 << bit)
+            };
+            self.record();
+        }
+
+        fn record(&mut self) {
+            let mut driven = [None; 8];
+            for (level, bit) in driven.iter_mut().zip(0..) {
+                *level = drives(self.get(bit));
+            }
+            self.driven.push(driven);
+        }
+
+        /// Do what `call` does to the pin at `bit`, in the same order
+        fn call(&mut self, bit: u8, call: PinCall) {
+            match call {
+                PinCall::FloatingInput => {
+                    self.write_ddr(bit, false);
+                    self.write_port(bit, false);
+                }
+                PinCall::PullUpInput => {
+                    self.write_ddr(bit, false);
+                    self.write_port(bit, true);
+                }
+                PinCall::Output => {
+                    self.write_port(bit, false);
+                    self.write_ddr(bit, true);
+                }
+                PinCall::OutputHigh => {
+                    self.write_port(bit, true);
+                    self.write_ddr(bit, true);
+                }
+            }
+        }
+
+        /// Get the pin at `bit` from `from` to `to` the way the firmware does it
+        fn transition(&mut self, bit: u8, from: PinBits, to: PinBits) {
+            for step in transition(from, to) {
+                self.call(bit, step.call());
+            }
+        }
+
+        fn get(&self, bit: u8) -> PinBits {
+            PinBits {
+                output: self.ddr & 1 << bit != 0,
+                high: self.port & 1 << bit != 0,
+            }
+        }
+    }
+
+    fn drives(bits: PinBits) -> Option<PinState> {
+        match bits {
+            PinBits { output: false, .. } => None,
+            PinBits { high: true, .. } => Some(PinState::High),
+            PinBits { high: false, .. } => Some(PinState::Low),
+        }
+    }
+
+    #[test]
+    fn steps_change_one_bit() {
+        //! A conversion writes both registers, which only can't glitch if one of them is left as it is
+        for from in ALL {
+            for to in ALL {
+                let mut last = from;
+                for step in transition(from, to) {
+                    assert!(
+                        step.output == last.output || step.high == last.high,
+                        "{:?} to {:?}",
+                        last,
+                        step
+                    );
+                    last = step;
+                }
+                assert_eq!(last, to);
+            }
+        }
+    }
+
+    #[test]
+    fn output_transitions_preload_level() {
+        //! An active-low reset line must not see a low level on its way from an input to a high output, and must end
+        //! up high
+        for from in [PinBits::FLOATING, PinBits::PULL_UP] {
+            let mut port = MockPort::new(&[(3, from)]);
+            port.transition(3, from, PinBits::output(PinState::High));
+            assert_eq!(port.get(3), PinBits::output(PinState::High));
+            assert!(port.driven.iter().all(|driven| driven[3] != Some(PinState::Low)));
+        }
+    }
+
+    #[test]
+    fn transitions_never_glitch() {
+        //! Between any two modes, a pin only ever drives the levels of its old or new mode, and its neighbours are
+        //! left alone
+        for from in ALL {
+            for to in ALL {
+                let mut port = MockPort::new(&[(3, from), (4, PinBits::output(PinState::High))]);
+                port.transition(3, from, to);
+                assert_eq!(port.get(3), to, "from {:?}", from);
+                for driven in &port.driven {
+                    assert!(driven[3].is_none() || driven[3] == drives(from) || driven[3] == drives(to));
+                    assert_eq!(driven[4], Some(PinState::High));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,717 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    time::{Duration, Instant},
+};
+
+use gpio_actions::{
+    to_frame, Action, Capabilities, DeviceInfo, Edge, Envelope, FrameReader, Identity, PatternSteps, PinInfo, PinLabel,
+    PinMask, PinMode, PinState, Playback, Port, Response, TransactionId, MAX_ACTION_FRAME_SIZE,
+    MAX_RESPONSE_FRAME_SIZE, NO_TRANSACTION, PATTERN_STEPS_LEN,
+};
+
+/// How long to wait for an answer to [`Action::Hello`] before asking again
+pub const HELLO_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long an action waits for its responses by default. Longer than any action keeps the firmware busy
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A [`Response`] together with the [`Action`] that caused it, if it could be matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reply {
+    pub id: TransactionId,
+    pub action: Option<Action>,
+    pub response: Response,
+}
+
+/// An action that was sent, and when to give up on its responses. `None` if they may take any time at all
+#[derive(Clone, Copy)]
+struct InFlight {
+    action: Action,
+    deadline: Option<Instant>,
+}
+
+/// Called for every [`Response::Event`] the firmware pushes, with the pin and the level it changed to
+pub type EventHandler = Box<dyn FnMut(PinLabel, PinState) + Send>;
+
+/// Called for every sample the firmware streams, with the levels of the streamed pins like in [`Response::InputMany`]
+pub type SampleHandler = Box<dyn FnMut(PinMask) + Send>;
+
+/// Talks to the expander firmware over any byte stream, usually a serial port.
+///
+/// Every [`Action`] is sent with a fresh [`TransactionId`], so many actions can be in flight at once and every
+/// [`Response`] is still matched to the action that caused it.
+pub struct Client<P> {
+    port: P,
+    next_id: TransactionId,
+    in_flight: HashMap<TransactionId, InFlight>,
+    reply_timeout: Duration,
+    actions_timed_out: usize,
+    frame_reader: FrameReader<MAX_RESPONSE_FRAME_SIZE>,
+    bytes_read: usize,
+    frames_rejected: usize,
+    device_info: Option<DeviceInfo>,
+    pin_modes: HashMap<PinLabel, PinMode>,
+    listing: Option<TransactionId>,
+    pin_order: Vec<PinLabel>,
+    /// How many pins the firmware said it would list, `None` if it doesn't say
+    pins_expected: Option<usize>,
+    pin_info: HashMap<PinLabel, PinInfo>,
+    ports: HashMap<Port, PinMask>,
+    /// How many ports the firmware said it would describe, `None` if it doesn't say
+    ports_expected: Option<usize>,
+    subscriptions: HashMap<PinLabel, Edge>,
+    captures: HashMap<PinLabel, Vec<(u32, PinState)>>,
+    event_handler: Option<EventHandler>,
+    streamed_pins: PinMask,
+    next_sequence: u16,
+    sample_frames_lost: usize,
+    sample_handler: Option<SampleHandler>,
+    heartbeat_ms: u16,
+    failsafe_tripped: Option<u32>,
+    identity: Option<Identity>,
+}
+
+impl<P> Client<P>
+where
+    P: Read + Write,
+{
+    pub fn new(port: P) -> Self {
+        Self {
+            port,
+            next_id: NO_TRANSACTION.wrapping_add(1),
+            in_flight: HashMap::new(),
+            reply_timeout: REPLY_TIMEOUT,
+            actions_timed_out: 0,
+            frame_reader: FrameReader::new(),
+            bytes_read: 0,
+            frames_rejected: 0,
+            device_info: None,
+            pin_modes: HashMap::new(),
+            listing: None,
+            pin_order: Vec::new(),
+            pins_expected: None,
+            pin_info: HashMap::new(),
+            ports: HashMap::new(),
+            ports_expected: None,
+            subscriptions: HashMap::new(),
+            captures: HashMap::new(),
+            event_handler: None,
+            streamed_pins: PinMask::NONE,
+            next_sequence: 0,
+            sample_frames_lost: 0,
+            sample_handler: None,
+            heartbeat_ms: 0,
+            failsafe_tripped: None,
+            identity: None,
+        }
+    }
+
+    pub fn port(&self) -> &P {
+        &self.port
+    }
+
+    pub fn bytes_read(&self) -> usize {
+        self.bytes_read
+    }
+
+    /// Number of frames from the firmware that were corrupted or incomplete
+    pub fn frames_rejected(&self) -> usize {
+        self.frames_rejected
+    }
+
+    /// Number of actions that were sent but not answered yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Number of actions that were given up on, because their responses didn't arrive in time
+    pub fn actions_timed_out(&self) -> usize {
+        self.actions_timed_out
+    }
+
+    /// Give up on actions whose responses didn't arrive within `timeout`, see [`REPLY_TIMEOUT`]. Their IDs can be used
+    /// again afterwards, so late responses are reported without an action instead of being matched to a new one
+    pub fn set_reply_timeout(&mut self, timeout: Duration) {
+        self.reply_timeout = timeout;
+    }
+
+    /// What the firmware reported about itself, once it has answered [`Action::Hello`]
+    pub fn device_info(&self) -> Option<DeviceInfo> {
+        self.device_info
+    }
+
+    /// Whether the firmware supports all of `capabilities`. Always false until it has answered [`Action::Hello`]
+    pub fn supports(&self, capabilities: Capabilities) -> bool {
+        matches!(self.device_info, Some(device_info) if device_info.capabilities.contains(capabilities))
+    }
+
+    /// The mode the firmware reported for `pin_label` last, if it reported one yet
+    pub fn pin_mode(&self, pin_label: PinLabel) -> Option<PinMode> {
+        self.pin_modes.get(&pin_label).copied()
+    }
+
+    /// What the firmware reported about `pin_label` in its answer to [`Action::ListPins`]
+    pub fn pin_info(&self, pin_label: PinLabel) -> Option<PinInfo> {
+        self.pin_info.get(&pin_label).copied()
+    }
+
+    /// Whether every pin of the last [`Action::ListPins`] has arrived. Always false after [`Action::List`], which
+    /// doesn't say how many pins there are
+    pub fn pins_listed(&self) -> bool {
+        self.pins_expected == Some(self.pin_order.len())
+    }
+
+    /// The [`PinMask`] containing `pin_labels`, in the order the firmware listed its pins last.
+    /// `None` if one of the pins wasn't listed or can't be addressed by a mask
+    pub fn pin_mask(&self, pin_labels: impl IntoIterator<Item = PinLabel>) -> Option<PinMask> {
+        pin_labels.into_iter().try_fold(PinMask::NONE, |mask, pin_label| {
+            let index = self.pin_order.iter().position(|&listed| listed == pin_label)?;
+            let single = PinMask::single(index);
+            (single != PinMask::NONE).then(|| mask.union(single))
+        })
+    }
+
+    /// Labels of the pins in `mask`, in the order the firmware listed its pins last
+    pub fn pin_labels(&self, mask: PinMask) -> Vec<PinLabel> {
+        self.pin_order
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| mask.contains(*index))
+            .map(|(_, &pin_label)| pin_label)
+            .collect()
+    }
+
+    /// The level of every pin in `mask` according to its bit in `levels`, like in [`Response::InputMany`]
+    pub fn pin_states(&self, mask: PinMask, levels: PinMask) -> Vec<(PinLabel, PinState)> {
+        self.pin_order
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| mask.contains(*index))
+            .map(|(index, &pin_label)| {
+                let state = if levels.contains(index) {
+                    PinState::High
+                } else {
+                    PinState::Low
+                };
+                (pin_label, state)
+            })
+            .collect()
+    }
+
+    /// Whether every port of the last [`Action::ListPorts`] has arrived. Always false after [`Action::Ports`], which
+    /// doesn't say how many ports there are
+    pub fn ports_listed(&self) -> bool {
+        self.ports_expected == Some(self.ports.len())
+    }
+
+    /// The port `pin_label` sits on, once the firmware has answered [`Action::ListPorts`] or [`Action::Ports`]. Only
+    /// pins on the same port can be written together by [`Action::OutputAtomic`]
+    pub fn pin_port(&self, pin_label: PinLabel) -> Option<Port> {
+        let index = self.pin_order.iter().position(|&listed| listed == pin_label)?;
+        self.ports
+            .iter()
+            .find(|(_, pins)| pins.contains(index))
+            .map(|(&port, _)| port)
+    }
+
+    /// The edges the firmware confirmed to report for `pin_label`, if it's subscribed to
+    pub fn subscription(&self, pin_label: PinLabel) -> Option<Edge> {
+        self.subscriptions.get(&pin_label).copied()
+    }
+
+    /// The time in microseconds and new level of every edge the firmware sent for `pin_label` after the last
+    /// [`Action::ReadCapture`]. Incomplete while not all chunks have arrived yet
+    pub fn capture(&self, pin_label: PinLabel) -> Option<&[(u32, PinState)]> {
+        self.captures.get(&pin_label).map(Vec::as_slice)
+    }
+
+    /// Call `handler` for every pin change event from now on, in addition to returning it from [`Client::receive`].
+    /// Events arrive whenever [`Client::receive`] is called, so keep calling it even if no actions are in flight
+    pub fn on_event(&mut self, handler: impl FnMut(PinLabel, PinState) + Send + 'static) {
+        self.event_handler = Some(Box::new(handler));
+    }
+
+    /// The pins the firmware confirmed to stream, see [`Action::Stream`]
+    pub fn streamed_pins(&self) -> PinMask {
+        self.streamed_pins
+    }
+
+    /// Number of [`Response::Samples`] frames that never arrived, judging by their sequence
+    pub fn sample_frames_lost(&self) -> usize {
+        self.sample_frames_lost
+    }
+
+    /// Call `handler` for every streamed sample from now on, like [`Client::on_event`]
+    pub fn on_samples(&mut self, handler: impl FnMut(PinMask) + Send + 'static) {
+        self.sample_handler = Some(Box::new(handler));
+    }
+
+    /// Send `action` without waiting for its response. Returns the ID its responses will carry.
+    ///
+    /// Once the firmware turned out to speak another protocol version, only [`Action::Hello`] is sent anymore,
+    /// because the firmware would misinterpret anything else.
+    pub fn send(&mut self, action: Action) -> io::Result<TransactionId> {
+        let incompatible = matches!(self.device_info, Some(device_info) if !device_info.is_compatible());
+        if incompatible && action != Action::Hello {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Firmware speaks an incompatible protocol version",
+            ));
+        }
+
+        self.expire();
+        let id = self.next_id()?;
+
+        let mut buffer = [0_u8; MAX_ACTION_FRAME_SIZE];
+        let frame = to_frame(&Envelope { id, payload: action }, &mut buffer).expect("Failed to serialize action!");
+        self.port.write_all(frame)?;
+        let deadline = Some(Instant::now() + self.reply_timeout);
+        self.in_flight.insert(id, InFlight { action, deadline });
+        Ok(id)
+    }
+
+    /// The next ID after the last one that isn't reserved or still in flight. Fails if every ID is still waiting for
+    /// its response, which only happens if the firmware stopped answering and actions without a deadline piled up
+    fn next_id(&mut self) -> io::Result<TransactionId> {
+        for _ in 0..=TransactionId::MAX {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            if id != NO_TRANSACTION && !self.in_flight.contains_key(&id) {
+                return Ok(id);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "Too many actions are waiting for their responses",
+        ))
+    }
+
+    /// Give up on the actions whose deadline has passed
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let before = self.in_flight.len();
+        self.in_flight
+            .retain(|_, in_flight| !matches!(in_flight.deadline, Some(deadline) if deadline <= now));
+        self.actions_timed_out += before - self.in_flight.len();
+    }
+
+    /// The heartbeat timeout the firmware confirmed in milliseconds, 0 if the failsafe is disabled. Some action has to
+    /// be sent within this time, or the firmware puts its outputs into their safe states
+    pub fn heartbeat_ms(&self) -> u16 {
+        self.heartbeat_ms
+    }
+
+    /// How many milliseconds the failsafe had been tripped for when the firmware last reported it
+    pub fn failsafe_tripped(&self) -> Option<u32> {
+        self.failsafe_tripped
+    }
+
+    /// The ID and name the firmware reported last, once it has answered [`Action::Identify`]
+    pub fn identity(&self) -> Option<Identity> {
+        self.identity
+    }
+
+    /// Find out which board is on the other end, to tell several of them apart. Keeps saying hello until the firmware
+    /// answers, as boards ignore everything while their bootloader runs after the port was opened. The port needs a
+    /// read timeout for this to return in time. `None` if the firmware doesn't answer within `timeout`, or can't
+    /// identify itself
+    pub fn identify(&mut self, timeout: Duration) -> io::Result<Option<Identity>> {
+        let start = Instant::now();
+        let mut hello_sent: Option<Instant> = None;
+        let mut identify_sent = false;
+        while start.elapsed() < timeout {
+            if self.identity.is_some() {
+                return Ok(self.identity);
+            }
+            match self.device_info {
+                None => {
+                    if !matches!(hello_sent, Some(sent) if sent.elapsed() < HELLO_INTERVAL) {
+                        self.send(Action::Hello)?;
+                        hello_sent = Some(Instant::now());
+                    }
+                }
+                Some(device_info) if !device_info.is_compatible() || !self.supports(Capabilities::IDENTITY) => {
+                    return Ok(None);
+                }
+                Some(_) if !identify_sent => {
+                    self.send(Action::Identify)?;
+                    identify_sent = true;
+                }
+                Some(_) => (),
+            }
+            self.receive();
+        }
+        Ok(None)
+    }
+
+    /// Send [`Action::LoadPattern`] followed by as many [`Action::AppendPattern`] as it takes to upload `steps`,
+    /// without waiting for their responses. Each step holds the levels of the pins in `mask`
+    pub fn load_pattern(&mut self, mask: PinMask, tick_us: u32, steps: &[PinMask]) -> io::Result<()> {
+        self.send(Action::LoadPattern(mask, tick_us))?;
+        for chunk in steps.chunks(PATTERN_STEPS_LEN) {
+            let mut levels = [PinMask::NONE; PATTERN_STEPS_LEN];
+            levels[..chunk.len()].copy_from_slice(chunk);
+            let count = chunk.len() as u8;
+            self.send(Action::AppendPattern(PatternSteps { count, levels }))?;
+        }
+        Ok(())
+    }
+
+    /// Read from the port until the next response is complete. Returns `None` if the port times out first
+    pub fn receive(&mut self) -> Option<Reply> {
+        self.expire();
+        let mut byte = [0_u8; 1];
+        while self.port.read_exact(&mut byte).is_ok() {
+            self.bytes_read += 1;
+            match self.frame_reader.push::<Envelope<Response>>(byte[0]) {
+                None => (),
+                Some(Ok(envelope)) => return Some(self.match_response(envelope)),
+                Some(Err(_error)) => self.frames_rejected += 1,
+            }
+        }
+        None
+    }
+
+    fn match_response(&mut self, envelope: Envelope<Response>) -> Reply {
+        let Envelope { id, payload: response } = envelope;
+        match response {
+            Response::Hello(device_info) => self.device_info = Some(device_info),
+            Response::Mode(pin_label, mode) | Response::Query(pin_label, mode, _) => {
+                self.pin_modes.insert(pin_label, mode);
+            }
+            // The firmware switches modes implicitly on these, and it boots with every pin in the default mode
+            Response::Output(pin_label, _)
+            | Response::Pulse(pin_label, ..)
+            | Response::PulseTrain(pin_label, _)
+            | Response::Blink(pin_label, _) => {
+                let mode = self.pin_modes.entry(pin_label).or_default();
+                *mode = mode.after_output();
+            }
+            Response::Input(pin_label, _) | Response::PulseWidth(pin_label, ..) | Response::Frequency(pin_label, _) => {
+                let mode = self.pin_modes.entry(pin_label).or_default();
+                *mode = mode.after_input();
+            }
+            Response::Analog(pin_label, _) => {
+                self.pin_modes.insert(pin_label, PinMode::Floating);
+            }
+            Response::Pwm(pin_label, _) => {
+                self.pin_modes.insert(pin_label, PinMode::Pwm);
+            }
+            Response::InputMany(mask, _) => {
+                for pin_label in self.pin_labels(mask) {
+                    let mode = self.pin_modes.entry(pin_label).or_default();
+                    *mode = mode.after_input();
+                }
+            }
+            Response::OutputMany(mask, _) => {
+                for pin_label in self.pin_labels(mask) {
+                    let mode = self.pin_modes.entry(pin_label).or_default();
+                    *mode = mode.after_output();
+                }
+            }
+            Response::Port(port, pins) => {
+                self.ports.insert(port, pins);
+            }
+            Response::PortCount(count) => {
+                self.ports_expected = Some(count as usize);
+                self.ports.clear();
+            }
+            Response::Subscribed(pin_label, edge) => {
+                self.subscriptions.insert(pin_label, edge);
+            }
+            Response::Unsubscribed(pin_label) => {
+                self.subscriptions.remove(&pin_label);
+            }
+            Response::Event(pin_label, state) => {
+                if let Some(handler) = self.event_handler.as_mut() {
+                    handler(pin_label, state);
+                }
+            }
+            Response::Capturing(pin_label, _) => {
+                self.captures.remove(&pin_label);
+            }
+            Response::Capture(pin_label, chunk) => {
+                let edges = self.captures.entry(pin_label).or_default();
+                if chunk.offset == 0 {
+                    edges.clear();
+                }
+                edges.extend(chunk.edges());
+            }
+            Response::Heartbeat(timeout_ms) => self.heartbeat_ms = timeout_ms,
+            Response::Identity(identity) => self.identity = Some(identity),
+            // The failsafe changed the outputs behind our back, so their modes are unknown now
+            Response::FailsafeTripped(tripped_ms) => {
+                self.failsafe_tripped = Some(tripped_ms);
+                self.pin_modes.retain(|_, mode| !mode.is_output());
+            }
+            Response::Streaming(pins, _) => {
+                self.streamed_pins = pins;
+                self.next_sequence = 0;
+            }
+            Response::Samples(frame) => {
+                self.sample_frames_lost += frame.sequence.wrapping_sub(self.next_sequence) as usize;
+                self.next_sequence = frame.sequence.wrapping_add(1);
+                if let Some(handler) = self.sample_handler.as_mut() {
+                    frame.samples(self.streamed_pins).for_each(handler);
+                }
+            }
+            // Masks refer to pins by their position in the list, which is unknown until the next List
+            Response::Labeled(pin_label, _) => {
+                self.pin_order.clear();
+                self.ports.clear();
+                self.ports_expected = None;
+                self.forget(pin_label);
+            }
+            Response::Unlabeled(pin_label, _) => {
+                self.pin_order.retain(|&listed| listed != pin_label);
+                self.ports.clear();
+                self.ports_expected = None;
+                self.forget(pin_label);
+            }
+            Response::Relabeled(pin_label, new_label) => {
+                for listed in self.pin_order.iter_mut().filter(|listed| **listed == pin_label) {
+                    *listed = new_label;
+                }
+                if let Some(mode) = self.pin_modes.remove(&pin_label) {
+                    self.pin_modes.insert(new_label, mode);
+                }
+                if let Some(edge) = self.subscriptions.remove(&pin_label) {
+                    self.subscriptions.insert(new_label, edge);
+                }
+                if let Some(edges) = self.captures.remove(&pin_label) {
+                    self.captures.insert(new_label, edges);
+                }
+                if let Some(info) = self.pin_info.remove(&pin_label) {
+                    self.pin_info.insert(
+                        new_label,
+                        PinInfo {
+                            label: new_label,
+                            ..info
+                        },
+                    );
+                }
+            }
+            // Masks refer to pins by their position in the list, so we have to remember it
+            Response::List(pin_label, _) => {
+                if self.listing != Some(id) {
+                    self.listing = Some(id);
+                    self.pins_expected = None;
+                    self.pin_order.clear();
+                }
+                self.pin_order.push(pin_label);
+            }
+            Response::PinCount(count) => {
+                self.listing = Some(id);
+                self.pins_expected = Some(count as usize);
+                self.pin_order.clear();
+                self.pin_info.clear();
+            }
+            Response::Pin(info) => {
+                self.pin_order.push(info.label);
+                self.pin_modes.insert(info.label, info.mode);
+                self.pin_info.insert(info.label, info);
+            }
+            _ => (),
+        }
+        let action = match response {
+            // Events aren't caused by any action, and NO_TRANSACTION is never in flight anyway
+            Response::Event(..) | Response::Samples(..) | Response::FailsafeTripped(..) => None,
+            // Every pin is listed in its own response, so the action has to stick around for the remaining ones
+            Response::List(..) => self.in_flight.get(&id).map(|in_flight| in_flight.action),
+            Response::PinCount(count) if count > 0 => self.in_flight.get(&id).map(|in_flight| in_flight.action),
+            Response::Pin(_) if !self.pins_listed() => self.in_flight.get(&id).map(|in_flight| in_flight.action),
+            Response::PortCount(count) if count > 0 => self.in_flight.get(&id).map(|in_flight| in_flight.action),
+            Response::Port(..) if self.ports_expected.is_some() && !self.ports_listed() => {
+                self.in_flight.get(&id).map(|in_flight| in_flight.action)
+            }
+            Response::Capture(_, chunk) if !chunk.is_last() => {
+                self.in_flight.get(&id).map(|in_flight| in_flight.action)
+            }
+            // PlaybackDone follows once the pattern is over, which may take any time at all
+            Response::Playing(Playback::Once | Playback::Loop) => self.in_flight.get_mut(&id).map(|in_flight| {
+                in_flight.deadline = None;
+                in_flight.action
+            }),
+            _ => self.in_flight.remove(&id).map(|in_flight| in_flight.action),
+        };
+        Reply { id, action, response }
+    }
+
+    /// Drop everything we know about the pin labeled `pin_label`, because the label now belongs to another pin or none
+    fn forget(&mut self, pin_label: PinLabel) {
+        self.pin_modes.remove(&pin_label);
+        self.subscriptions.remove(&pin_label);
+        self.captures.remove(&pin_label);
+        self.pin_info.remove(&pin_label);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use gpio_actions::PinCapabilities;
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    /// Stands in for the serial port. Reads time out once everything the firmware "sent" was read
+    #[derive(Default)]
+    struct MockPort {
+        incoming: VecDeque<u8>,
+        written: Vec<u8>,
+    }
+
+    impl Read for MockPort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.incoming.is_empty() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            let count = buf.len().min(self.incoming.len());
+            for (byte, incoming) in buf.iter_mut().zip(self.incoming.drain(..count)) {
+                *byte = incoming;
+            }
+            Ok(count)
+        }
+    }
+
+    impl Write for MockPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Let the firmware send `response` with `id`
+    fn respond(client: &mut Client<MockPort>, id: TransactionId, response: Response) {
+        let mut buffer = [0_u8; MAX_RESPONSE_FRAME_SIZE];
+        let frame = to_frame(&Envelope { id, payload: response }, &mut buffer).unwrap();
+        client.port.incoming.extend(frame.iter().copied());
+    }
+
+    #[test]
+    fn replies_match_their_actions() {
+        //! The firmware may answer in any order, every response must still find its action
+        let mut client = Client::new(MockPort::default());
+        let output = client.send(Action::Output('1', PinState::High)).unwrap();
+        let input = client.send(Action::Input('2')).unwrap();
+        assert_ne!(output, input);
+        assert_eq!(client.in_flight(), 2);
+
+        respond(&mut client, input, Response::Input('2', PinState::Low));
+        respond(&mut client, output, Response::Output('1', PinState::High));
+        let reply = client.receive().unwrap();
+        assert_eq!((reply.id, reply.action), (input, Some(Action::Input('2'))));
+        let reply = client.receive().unwrap();
+        assert_eq!(
+            (reply.id, reply.action),
+            (output, Some(Action::Output('1', PinState::High)))
+        );
+        assert_eq!(client.in_flight(), 0);
+        assert!(client.receive().is_none());
+    }
+
+    #[test]
+    fn events_belong_to_no_action() {
+        //! Events are pushed by the firmware on its own and must neither match nor complete an action
+        let mut client = Client::new(MockPort::default());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let handled = events.clone();
+        client.on_event(move |pin_label, state| handled.lock().unwrap().push((pin_label, state)));
+        let id = client.send(Action::Subscribe('1', Edge::Both)).unwrap();
+
+        respond(&mut client, NO_TRANSACTION, Response::Event('1', PinState::High));
+        let reply = client.receive().unwrap();
+        assert_eq!(reply.action, None);
+        assert_eq!(*events.lock().unwrap(), [('1', PinState::High)]);
+        assert_eq!(client.in_flight(), 1);
+
+        respond(&mut client, id, Response::Subscribed('1', Edge::Both));
+        assert_eq!(
+            client.receive().unwrap().action,
+            Some(Action::Subscribe('1', Edge::Both))
+        );
+    }
+
+    #[test]
+    fn multi_part_replies_keep_their_action() {
+        //! The action stays in flight until the last of its responses
+        let mut client = Client::new(MockPort::default());
+        let id = client.send(Action::ListPins).unwrap();
+        let pin = |index: u8, label| PinInfo {
+            index,
+            label,
+            name: "D2".parse().unwrap(),
+            mode: PinMode::PullUp,
+            capabilities: PinCapabilities::DIGITAL,
+        };
+        respond(&mut client, id, Response::PinCount(2));
+        respond(&mut client, id, Response::Pin(pin(0, '1')));
+        respond(&mut client, id, Response::Pin(pin(1, '2')));
+        for _ in 0..3 {
+            assert_eq!(client.receive().unwrap().action, Some(Action::ListPins));
+        }
+        assert!(client.pins_listed());
+        assert_eq!(client.in_flight(), 0);
+
+        let id = client.send(Action::ListPorts).unwrap();
+        respond(&mut client, id, Response::PortCount(2));
+        respond(&mut client, id, Response::Port(Port::B, PinMask::single(0)));
+        respond(&mut client, id, Response::Port(Port::D, PinMask::single(1)));
+        for _ in 0..3 {
+            assert_eq!(client.receive().unwrap().action, Some(Action::ListPorts));
+        }
+        assert!(client.ports_listed());
+        assert_eq!(client.pin_port('2'), Some(Port::D));
+        assert_eq!(client.in_flight(), 0);
+    }
+
+    #[test]
+    fn stale_actions_expire() {
+        //! An unanswered action must not match a response that arrives after its ID was used again
+        let mut client = Client::new(MockPort::default());
+        client.set_reply_timeout(Duration::ZERO);
+        let id = client.send(Action::Input('1')).unwrap();
+        assert!(client.receive().is_none());
+        assert_eq!(client.in_flight(), 0);
+        assert_eq!(client.actions_timed_out(), 1);
+
+        respond(&mut client, id, Response::Input('1', PinState::High));
+        assert_eq!(client.receive().unwrap().action, None);
+    }
+
+    #[test]
+    fn ids_skip_actions_in_flight() {
+        //! After the IDs wrap around, neither the reserved one nor those still waiting for responses are used
+        let mut client = Client::new(MockPort::default());
+        let waiting = client.send(Action::Input('1')).unwrap();
+        client.next_id = NO_TRANSACTION;
+        let id = client.send(Action::Input('2')).unwrap();
+        assert_ne!(id, NO_TRANSACTION);
+        assert_ne!(id, waiting);
+    }
+
+    #[test]
+    fn ids_run_out() {
+        //! Once every ID waits for a response, sending fails instead of searching forever
+        let mut client = Client::new(MockPort::default());
+        for id in 1..=TransactionId::MAX {
+            let in_flight = InFlight {
+                action: Action::Input('1'),
+                deadline: None,
+            };
+            client.in_flight.insert(id, in_flight);
+        }
+        let error = client.send(Action::Input('2')).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert!(client.port.written.is_empty());
+
+        client.in_flight.remove(&42);
+        assert_eq!(client.send(Action::Input('2')).unwrap(), 42);
+    }
+}
@@ -1,19 +1,58 @@
 use std::{
     collections::{HashMap, VecDeque},
-    io::{Read, Write},
-    time::Duration,
+    io,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use egui::{mutex::Mutex, ComboBox, TextEdit};
-use gpio_actions::{Action, PinLabel, PinName, PinState, Response, TryFromIter, MAX_RESPONSE_WIRE_SIZE};
+use egui::{mutex::Mutex, Button, Color32, ComboBox, DragValue, Slider, TextEdit};
+use gpio_actions::{
+    min_sample_period_us, Action, AnalogConfig, AnalogReference, Blink, Capabilities, Debounce, DeviceId, DeviceInfo,
+    DeviceName, Edge, Identity, PinCapabilities, PinLabel, PinMask, PinMode, PinName, PinState, Playback, Port,
+    PwmPrescaler, Response, ANALOG_MAX, BAUD_RATE, MAX_BLOCKING_US, MAX_CAPTURE_EDGES, MAX_DEVICE_NAME_LEN,
+    MAX_GATE_MS, MAX_PATTERN_TICK_US, MAX_SAMPLE_PERIOD_US, MIN_PATTERN_TICK_US, PROTOCOL_VERSION,
+};
 use serialport::{SerialPort, SerialPortInfo};
 
+mod client;
+pub use client::{Client, EventHandler, Reply, SampleHandler, HELLO_INTERVAL, REPLY_TIMEOUT};
+
 #[derive(serde::Deserialize, serde::Serialize, Default, Debug, PartialEq, Eq, PartialOrd)]
 enum ActionType {
     #[default]
     Output,
     Input,
     List,
+    Hello,
+    SetMode,
+    AnalogRead,
+    Pwm,
+    Debounce,
+    Pulse,
+    Blink,
+    MeasurePulse,
+    CountEdges,
+    ReadCount,
+    MeasureFrequency,
+    Capture,
+    ReadCapture,
+    Stream,
+    LoadPattern,
+    Play,
+    Heartbeat,
+    SafeState,
+    PowerOn,
+    ReadPowerOn,
+    FactoryReset,
+    Label,
+    Unlabel,
+    Relabel,
+    Identify,
+    SetName,
+    ListPins,
+    Query,
+    SetId,
 }
 
 /// We derive Deserialize/Serialize so we can persist app state on shutdown.
@@ -23,40 +62,86 @@ pub struct TemplateApp {
     selected_action_type: ActionType,
     pin_label: String,
     pin_high: bool,
+    pin_mode: PinMode,
+    analog_config: AnalogConfig,
+    pwm_duty: u8,
+    debounce_millis: u16,
+    pulse_width_us: u32,
+    blink_period_ms: u16,
+    blink_duty: u8,
+    measure_timeout_us: u32,
+    count_edge: Option<Edge>,
+    reset_count: bool,
+    gate_ms: u16,
+    capture_limit: u16,
+    stream_period_us: u16,
+    pattern_pins: String,
+    pattern_steps: String,
+    pattern_tick_us: u32,
+    playback: Playback,
+    heartbeat_ms: u16,
+    safe_state: Option<PinState>,
+    pin_name: String,
+    new_pin_label: String,
+    device_name: String,
+    device_id: String,
     #[serde(skip)]
-    serial_port: Mutex<Option<serialport::TTYPort>>,
-    #[serde(skip)]
-    serial_responses: VecDeque<Response>,
+    client: Mutex<Option<Client<serialport::TTYPort>>>,
     #[serde(skip)]
-    bytes_read: usize,
+    serial_responses: VecDeque<Reply>,
     #[serde(skip)]
     pin_map: HashMap<PinLabel, PinName>,
+    #[serde(skip)]
+    list_sent: bool,
+    #[serde(skip)]
+    hello_sent: Option<Instant>,
+    #[serde(skip)]
+    heartbeat_sent: Option<Instant>,
+    #[serde(skip)]
+    identify_sent: bool,
+    /// The boards found by [`TemplateApp::identify_boards`], by the name of their port
+    #[serde(skip)]
+    identities: Arc<Mutex<HashMap<String, Identity>>>,
+    #[serde(skip)]
+    pin_levels: HashMap<PinLabel, PinState>,
+    #[serde(skip)]
+    analog_readings: HashMap<PinLabel, u16>,
+    #[serde(skip)]
+    pwm_duties: HashMap<PinLabel, u8>,
+    #[serde(skip)]
+    pwm_prescalers: HashMap<PinLabel, PwmPrescaler>,
 }
 
 const DEFAULT_PIN_LABEL: char = '?';
 
-struct SerialIter<'a> {
-    port: &'a mut serialport::TTYPort,
-    bytes_read: usize,
-}
-
-impl<'a> SerialIter<'a> {
-    pub fn new(port: &'a mut serialport::TTYPort) -> Self {
-        Self { port, bytes_read: 0 }
-    }
-}
-
-impl<'a> Iterator for SerialIter<'a> {
-    type Item = u8;
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut buf = [0_u8; 1];
-        if self.port.read_exact(&mut buf).is_err() {
-            return None;
-        }
-        self.bytes_read += 1;
-        Some(buf[0])
-    }
-}
+const PIN_MODES: [PinMode; 5] = [
+    PinMode::Floating,
+    PinMode::PullUp,
+    PinMode::Output,
+    PinMode::OpenDrain,
+    PinMode::Pwm,
+];
+
+const ANALOG_REFERENCES: [AnalogReference; 3] = [
+    AnalogReference::AVcc,
+    AnalogReference::Internal1V1,
+    AnalogReference::External,
+];
+
+const EDGES: [Option<Edge>; 4] = [None, Some(Edge::Rising), Some(Edge::Falling), Some(Edge::Both)];
+
+const PWM_PRESCALERS: [PwmPrescaler; 7] = [
+    PwmPrescaler::Direct,
+    PwmPrescaler::Prescale8,
+    PwmPrescaler::Prescale32,
+    PwmPrescaler::Prescale64,
+    PwmPrescaler::Prescale128,
+    PwmPrescaler::Prescale256,
+    PwmPrescaler::Prescale1024,
+];
+
+/// How long to wait for a board to identify itself, which includes waiting for its bootloader
+const IDENTIFY_TIMEOUT: Duration = Duration::from_secs(4);
 
 impl TemplateApp {
     /// Called once before the first frame.
@@ -74,54 +159,334 @@ impl TemplateApp {
     }
 
     fn read_from_serial(&mut self, lines: usize) {
-        let mut port_mutex_guard = self.serial_port.lock();
-        let serial_port = port_mutex_guard.as_mut().expect("Not connected to serial port?");
-
-        let mut serial_iter = SerialIter::new(serial_port);
+        let mut client_mutex_guard = self.client.lock();
+        let client = client_mutex_guard.as_mut().expect("Not connected to serial port?");
 
-        let response_result = Response::try_from_iter::<MAX_RESPONSE_WIRE_SIZE>(&mut serial_iter);
-        self.bytes_read += serial_iter.bytes_read;
-
-        if let Ok(response) = response_result {
-            self.serial_responses.push_back(response);
+        if let Some(reply) = client.receive() {
+            self.serial_responses.push_back(reply);
             while self.serial_responses.len() > lines {
                 self.serial_responses.pop_front();
             }
 
-            if let Response::List(label, name) = response {
-                self.pin_map.insert(label, name);
+            match reply.response {
+                Response::List(label, name) => {
+                    self.pin_map.insert(label, name);
+                }
+                Response::Pin(info) => {
+                    self.pin_map.insert(info.label, info.name);
+                }
+                // The pin list has to be fetched again, which happens as soon as it's empty
+                Response::Labeled(..) | Response::Unlabeled(..) | Response::Relabeled(..) => {
+                    self.pin_map.clear();
+                    self.list_sent = false;
+                }
+                Response::Input(label, state)
+                | Response::Output(label, state)
+                | Response::Event(label, state)
+                | Response::Query(label, _, state) => {
+                    self.pin_levels.insert(label, state);
+                }
+                Response::InputMany(mask, levels)
+                | Response::OutputMany(mask, levels)
+                | Response::OutputAtomic(mask, levels) => {
+                    self.pin_levels.extend(client.pin_states(mask, levels));
+                }
+                Response::Samples(frame) => {
+                    let pins = client.streamed_pins();
+                    if let Some(levels) = frame.samples(pins).last() {
+                        self.pin_levels.extend(client.pin_states(pins, levels));
+                    }
+                }
+                Response::Analog(label, value) => {
+                    self.analog_readings.insert(label, value);
+                }
+                Response::Pwm(label, duty) => {
+                    self.pwm_duties.insert(label, duty);
+                }
+                Response::PwmConfig(label, prescaler) => {
+                    self.pwm_prescalers.insert(label, prescaler);
+                }
+                _ => (),
             }
         }
     }
 
     fn connect(&mut self, port: SerialPortInfo) {
-        let tty_port = serialport::new(port.port_name, 57600)
+        let tty_port = serialport::new(port.port_name, BAUD_RATE)
             .timeout(Duration::from_millis(10))
             .open_native()
             .expect("Failed to open serial port!");
 
-        self.serial_port = Mutex::new(Some(tty_port));
+        self.client = Mutex::new(Some(Client::new(tty_port)));
     }
 
     fn send_action(&self, action: Action) {
-        let mut port_mutex_guard = self.serial_port.lock();
-        let serial_port = port_mutex_guard.as_mut().expect("Not connected to serial port?");
+        let mut client_mutex_guard = self.client.lock();
+        let client = client_mutex_guard.as_mut().expect("Not connected to serial port?");
+
+        expect_sent(client.send(action).map(drop));
+    }
+
+    fn load_pattern(&self, mask: PinMask, tick_us: u32, steps: &[PinMask]) {
+        let mut client_mutex_guard = self.client.lock();
+        let client = client_mutex_guard.as_mut().expect("Not connected to serial port?");
+
+        expect_sent(client.load_pattern(mask, tick_us, steps));
+    }
+
+    fn device_info(&self) -> Option<DeviceInfo> {
+        self.client.lock().as_ref().and_then(|client| client.device_info())
+    }
+
+    /// The board resets when the port is opened and ignores everything until its bootloader is done,
+    /// so we have to keep asking until it answers
+    fn say_hello(&mut self) {
+        if !matches!(self.hello_sent, Some(sent) if sent.elapsed() < HELLO_INTERVAL) {
+            self.send_action(Action::Hello);
+            self.hello_sent = Some(Instant::now());
+        }
+    }
+
+    /// Once the firmware expects heartbeats, we have to send them even while the user does nothing
+    fn keep_alive(&mut self, ctx: &egui::Context) {
+        let heartbeat_ms = self.client.lock().as_ref().map_or(0, |client| client.heartbeat_ms());
+        if heartbeat_ms == 0 {
+            return;
+        }
+        ctx.request_repaint();
+        let interval = Duration::from_millis(heartbeat_ms as u64) / 2;
+        if !matches!(self.heartbeat_sent, Some(sent) if sent.elapsed() < interval) {
+            self.send_action(Action::Heartbeat(heartbeat_ms));
+            self.heartbeat_sent = Some(Instant::now());
+        }
+    }
+
+    /// Ask the board on each of `ports` who it is in the background. This resets the boards, just like connecting does
+    fn identify_boards(&self, ports: Vec<SerialPortInfo>, ctx: &egui::Context) {
+        for port in ports {
+            let identities = self.identities.clone();
+            let ctx = ctx.clone();
+            std::thread::spawn(move || {
+                let tty_port = serialport::new(&port.port_name, BAUD_RATE)
+                    .timeout(Duration::from_millis(10))
+                    .open_native();
+                let identity = tty_port
+                    .ok()
+                    .and_then(|tty_port| Client::new(tty_port).identify(IDENTIFY_TIMEOUT).ok().flatten());
+                if let Some(identity) = identity {
+                    identities.lock().insert(port.port_name, identity);
+                    ctx.request_repaint();
+                }
+            });
+        }
+    }
+
+    fn supports(&self, capabilities: Capabilities) -> bool {
+        matches!(self.client.lock().as_ref(), Some(client) if client.supports(capabilities))
+    }
+
+    fn pin_mode(&self, pin_label: PinLabel) -> Option<PinMode> {
+        self.client
+            .lock()
+            .as_ref()
+            .and_then(|client| client.pin_mode(pin_label))
+    }
+
+    /// Shortest period every pin can be streamed at, see [`min_sample_period_us`]
+    fn min_stream_period_us(&self) -> u16 {
+        let pin_count = self
+            .device_info()
+            .map_or(32, |device_info| device_info.pin_count as u32);
+        min_sample_period_us(pin_count)
+    }
+
+    /// What `pin_label` can do. Firmware that can't tell is assumed to support everything on every pin
+    fn pin_capabilities(&self, pin_label: PinLabel) -> PinCapabilities {
+        match self
+            .client
+            .lock()
+            .as_ref()
+            .and_then(|client| client.pin_info(pin_label))
+        {
+            Some(info) => info.capabilities,
+            None => PinCapabilities(u8::MAX),
+        }
+    }
+
+    fn pin_port(&self, pin_label: PinLabel) -> Option<Port> {
+        self.client
+            .lock()
+            .as_ref()
+            .and_then(|client| client.pin_port(pin_label))
+    }
+
+    fn subscription(&self, pin_label: PinLabel) -> Option<Edge> {
+        self.client
+            .lock()
+            .as_ref()
+            .and_then(|client| client.subscription(pin_label))
+    }
+
+    /// The pins and steps of the pattern typed into the UI. Each step is a string of 0s and 1s, one for every pin in
+    /// the order they were typed in. `None` until all of the pins were listed
+    fn pattern(&self) -> Option<(PinMask, Vec<PinMask>)> {
+        let client = self.client.lock();
+        let client = client.as_ref()?;
+        let pins: Vec<PinLabel> = self.pattern_pins.chars().collect();
+        let mask = client.pin_mask(pins.iter().copied())?;
+        let steps = self
+            .pattern_steps
+            .split_whitespace()
+            .map(|step| {
+                let high = pins.iter().zip(step.chars()).filter(|(_, level)| *level == '1');
+                client.pin_mask(high.map(|(&pin_label, _)| pin_label))
+            })
+            .collect::<Option<_>>()?;
+        Some((mask, steps))
+    }
+
+    /// The edges captured on `pin_label`, one per line
+    fn capture_text(&self, pin_label: PinLabel) -> Option<String> {
+        let client = self.client.lock();
+        let edges = client.as_ref()?.capture(pin_label)?;
+        let lines: Vec<String> = edges
+            .iter()
+            .map(|(micros, state)| format!("{:?} at {}µs", state, micros))
+            .collect();
+        Some(format!("Captured {} edges\n{}", edges.len(), lines.join("\n")))
+    }
 
-        let serialized_list = postcard::to_stdvec(&action).expect("Failed to serialize action!");
-        serial_port.write_all(&serialized_list).expect("Failed to send action!");
+    /// The action the UI is set up to send. `None` while a field that can't be fixed up silently doesn't parse, so
+    /// nothing like an all-zero ID ends up stored on the device
+    fn action(&self, pin_label: PinLabel, pin_state: PinState) -> Option<Action> {
+        let action = match self.selected_action_type {
+            ActionType::Output => Action::Output(pin_label, pin_state),
+            ActionType::Input => Action::Input(pin_label),
+            ActionType::List => Action::List,
+            ActionType::ListPins => Action::ListPins,
+            ActionType::Query => Action::Query(pin_label),
+            ActionType::Hello => Action::Hello,
+            ActionType::SetMode => Action::SetMode(pin_label, self.pin_mode),
+            ActionType::AnalogRead => Action::AnalogRead(pin_label),
+            ActionType::Pwm => Action::Pwm(pin_label, self.pwm_duty),
+            ActionType::Pulse => Action::Pulse(pin_label, pin_state, self.pulse_width_us),
+            ActionType::MeasurePulse => Action::MeasurePulse(pin_label, pin_state, self.measure_timeout_us),
+            ActionType::CountEdges => Action::CountEdges(pin_label, self.count_edge),
+            ActionType::ReadCount => Action::ReadCount(pin_label, self.reset_count),
+            ActionType::MeasureFrequency => Action::MeasureFrequency(pin_label, self.gate_ms),
+            ActionType::Capture => Action::Capture(pin_label, self.capture_limit),
+            ActionType::ReadCapture => Action::ReadCapture(pin_label),
+            // Streams every pin, the pin list shows their latest levels
+            ActionType::Stream => match self.stream_period_us {
+                0 => Action::Stream(PinMask::NONE, 0),
+                period_us => Action::Stream(PinMask::ALL, period_us.max(self.min_stream_period_us())),
+            },
+            // The steps are sent in separate actions after this one
+            ActionType::LoadPattern => {
+                let (mask, _) = self.pattern().unwrap_or_default();
+                Action::LoadPattern(mask, self.pattern_tick_us)
+            }
+            ActionType::Play => Action::Play(self.playback),
+            ActionType::Heartbeat => Action::Heartbeat(self.heartbeat_ms),
+            ActionType::SafeState => Action::SafeState(pin_label, self.safe_state),
+            ActionType::PowerOn => Action::PowerOn(pin_label, self.pin_mode, pin_state),
+            ActionType::ReadPowerOn => Action::ReadPowerOn(pin_label),
+            ActionType::FactoryReset => Action::FactoryReset,
+            ActionType::Identify => Action::Identify,
+            ActionType::SetName => Action::SetName(DeviceName::new(&self.device_name)?),
+            ActionType::SetId => Action::SetId(DeviceId::parse(&self.device_id)?),
+            ActionType::Label => Action::Label(pin_label, PinName::from_str(&self.pin_name).unwrap()),
+            ActionType::Unlabel => Action::Unlabel(pin_label),
+            ActionType::Relabel => {
+                let new_label = self.new_pin_label.chars().next().unwrap_or(DEFAULT_PIN_LABEL);
+                Action::Relabel(pin_label, new_label)
+            }
+            ActionType::Blink => Action::Blink(
+                pin_label,
+                Blink {
+                    period_ms: self.blink_period_ms,
+                    duty: self.blink_duty,
+                    count: None,
+                },
+            ),
+            ActionType::Debounce => Action::Debounce(
+                pin_label,
+                match self.debounce_millis {
+                    0 => Debounce::Off,
+                    millis => Debounce::Millis(millis),
+                },
+            ),
+        };
+        Some(action)
     }
 
     fn build_pin_list(&mut self, ui: &mut egui::Ui) {
         if self.pin_map.is_empty() {
-            self.send_action(Action::List);
+            // A board without any labeled pins never answers with a pin, so the list may only be asked for once
+            if !self.list_sent {
+                if self.supports(Capabilities::PIN_INFO) {
+                    self.send_action(Action::ListPins);
+                } else {
+                    self.send_action(Action::List);
+                }
+                if self.supports(Capabilities::LIST_PORTS) {
+                    self.send_action(Action::ListPorts);
+                } else if self.supports(Capabilities::ATOMIC_PORTS) {
+                    self.send_action(Action::Ports);
+                }
+                self.list_sent = true;
+            }
             return;
         }
 
+        let analog_input = self.supports(Capabilities::ANALOG_INPUT);
+        let pwm = self.supports(Capabilities::PWM);
+        let events = self.supports(Capabilities::EVENTS);
+        let query = self.supports(Capabilities::QUERY);
+        let set_mode = self.supports(Capabilities::SET_MODE);
+        let many_pins = self.supports(Capabilities::MANY_PINS);
         ui.vertical(|ui| {
+            if many_pins && ui.button("Read all").clicked() {
+                self.send_action(Action::InputMany(PinMask::ALL));
+            }
+            if analog_input {
+                ui.horizontal(|ui| {
+                    ui.label("ADC reference");
+                    ComboBox::from_id_source("analog_reference")
+                        .selected_text(format!("{:?}", self.analog_config.reference))
+                        .show_ui(ui, |ui| {
+                            for reference in ANALOG_REFERENCES {
+                                ui.selectable_value(
+                                    &mut self.analog_config.reference,
+                                    reference,
+                                    format!("{:?}", reference),
+                                );
+                            }
+                        });
+                    ui.label("Samples");
+                    ui.add(DragValue::new(&mut self.analog_config.samples).clamp_range(1..=u8::MAX));
+                    if ui.button("Configure ADC").clicked() {
+                        self.send_action(Action::ConfigureAnalog(self.analog_config));
+                    }
+                });
+            }
+
             for (&pin_label, &pin_name) in &self.pin_map {
+                let capabilities = self.pin_capabilities(pin_label);
                 ui.horizontal(|ui| {
                     ui.heading(String::from(pin_name));
                     ui.label(String::from(pin_label));
+                    if let Some(port) = self.pin_port(pin_label) {
+                        ui.label(format!("PORT{:?}", port));
+                    }
+                    let current_mode = self.pin_mode(pin_label).unwrap_or_default();
+                    if set_mode {
+                        let mut selected_mode = current_mode;
+                        pin_mode_selector(ui, pin_label, &mut selected_mode);
+                        if selected_mode != current_mode {
+                            self.send_action(Action::SetMode(pin_label, selected_mode));
+                        }
+                    }
                     if ui.button("Set High").clicked() {
                         self.send_action(Action::Output(pin_label, PinState::High));
                     }
@@ -131,6 +496,54 @@ impl TemplateApp {
                     if ui.button("Input").clicked() {
                         self.send_action(Action::Output(pin_label, PinState::Low));
                     }
+                    if query && ui.button("Query").clicked() {
+                        self.send_action(Action::Query(pin_label));
+                    }
+                    if let Some(state) = self.pin_levels.get(&pin_label) {
+                        ui.label(format!("{:?}", state));
+                    }
+                    if events && capabilities.contains(PinCapabilities::INTERRUPT) {
+                        let current_edge = self.subscription(pin_label);
+                        if current_edge.is_some() {
+                            // Events only get read while the UI is being repainted
+                            ui.ctx().request_repaint();
+                        }
+                        let mut edge = current_edge;
+                        edge_selector(ui, ("edge", pin_label), &mut edge);
+                        if edge != current_edge {
+                            match edge {
+                                Some(edge) => self.send_action(Action::Subscribe(pin_label, edge)),
+                                None => self.send_action(Action::Unsubscribe(pin_label)),
+                            }
+                        }
+                    }
+                    if analog_input && capabilities.contains(PinCapabilities::ANALOG) {
+                        if ui.button("Analog").clicked() {
+                            self.send_action(Action::AnalogRead(pin_label));
+                        }
+                        if let Some(value) = self.analog_readings.get(&pin_label) {
+                            ui.label(format!("{}/{}", value, ANALOG_MAX));
+                        }
+                    }
+                    if pwm && capabilities.contains(PinCapabilities::PWM) && current_mode == PinMode::Pwm {
+                        let mut duty = self.pwm_duties.get(&pin_label).copied().unwrap_or_default();
+                        if ui.add(Slider::new(&mut duty, 0..=u8::MAX)).changed() {
+                            self.pwm_duties.insert(pin_label, duty);
+                            self.send_action(Action::Pwm(pin_label, duty));
+                        }
+                        let current_prescaler = self.pwm_prescalers.get(&pin_label).copied().unwrap_or_default();
+                        let mut prescaler = current_prescaler;
+                        ComboBox::from_id_source(("pwm_prescaler", pin_label))
+                            .selected_text(format!("{:?}", prescaler))
+                            .show_ui(ui, |ui| {
+                                for option in PWM_PRESCALERS {
+                                    ui.selectable_value(&mut prescaler, option, format!("{:?}", option));
+                                }
+                            });
+                        if prescaler != current_prescaler {
+                            self.send_action(Action::ConfigurePwm(pin_label, prescaler));
+                        }
+                    }
                 });
             }
         });
@@ -139,13 +552,35 @@ impl TemplateApp {
     fn serial_output_text(&mut self, ui: &mut egui::Ui, lines: usize) {
         self.read_from_serial(lines);
 
-        ui.label(format!("Bytes read: {}", self.bytes_read));
-        for response in &self.serial_responses {
-            ui.label(format!("{:?}", response));
+        if let Some(client) = self.client.lock().as_ref() {
+            ui.label(format!("Bytes read: {}", client.bytes_read()));
+            ui.label(format!("Frames rejected: {}", client.frames_rejected()));
+            ui.label(format!("Actions in flight: {}", client.in_flight()));
+            ui.label(format!("Actions timed out: {}", client.actions_timed_out()));
+            if client.streamed_pins() != PinMask::NONE {
+                ui.label(format!("Sample frames lost: {}", client.sample_frames_lost()));
+                // Samples only get read while the UI is being repainted
+                ui.ctx().request_repaint();
+            }
+        }
+        for reply in &self.serial_responses {
+            match reply.action {
+                Some(action) => ui.label(format!("#{} {:?} => {:?}", reply.id, action, reply.response)),
+                None => ui.label(format!("#{} ??? => {:?}", reply.id, reply.response)),
+            };
         }
     }
 }
 
+/// Panics if sending failed, unless it's only because every ID is waiting for a response. The UI shows how many
+/// actions are in flight, and sending works again once they time out
+fn expect_sent(result: io::Result<()>) {
+    match result {
+        Err(error) if error.kind() == io::ErrorKind::WouldBlock => (),
+        result => result.expect("Failed to send action!"),
+    }
+}
+
 fn single_character_text<S>(ui: &mut egui::Ui, text: &mut S)
 where
     S: egui::TextBuffer,
@@ -158,7 +593,31 @@ where
     );
 }
 
-fn format_port(port: &SerialPortInfo) -> String {
+fn pin_mode_selector(ui: &mut egui::Ui, id_source: impl std::hash::Hash, mode: &mut PinMode) {
+    ComboBox::from_id_source(id_source)
+        .selected_text(format!("{:?}", mode))
+        .show_ui(ui, |ui| {
+            for option in PIN_MODES {
+                ui.selectable_value(mode, option, format!("{:?}", option));
+            }
+        });
+}
+
+fn edge_selector(ui: &mut egui::Ui, id_source: impl std::hash::Hash, edge: &mut Option<Edge>) {
+    let text = |edge: Option<Edge>| match edge {
+        Some(edge) => format!("{:?} edges", edge),
+        None => "No edges".to_owned(),
+    };
+    ComboBox::from_id_source(id_source)
+        .selected_text(text(*edge))
+        .show_ui(ui, |ui| {
+            for option in EDGES {
+                ui.selectable_value(edge, option, text(option));
+            }
+        });
+}
+
+fn format_port(port: &SerialPortInfo, identity: Option<&Identity>) -> String {
     let path = port.port_name.clone();
     let name;
     if let serialport::SerialPortType::UsbPort(port_info) = port.port_type.clone() {
@@ -166,7 +625,13 @@ fn format_port(port: &SerialPortInfo) -> String {
     } else {
         name = format!("{:?}", port.port_type)
     }
-    format!("{} ({})", path, name)
+    match identity {
+        Some(identity) if !identity.name.is_empty() => {
+            format!("{} ({}) {} [{}]", path, name, identity.name, identity.id)
+        }
+        Some(identity) => format!("{} ({}) [{}]", path, name, identity.id),
+        None => format!("{} ({})", path, name),
+    }
 }
 
 impl eframe::App for TemplateApp {
@@ -189,6 +654,39 @@ impl eframe::App for TemplateApp {
                         ui.selectable_value(&mut self.selected_action_type, ActionType::Output, "Output");
                         ui.selectable_value(&mut self.selected_action_type, ActionType::Input, "Input");
                         ui.selectable_value(&mut self.selected_action_type, ActionType::List, "List");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::ListPins, "ListPins");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::Query, "Query");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::Hello, "Hello");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::SetMode, "SetMode");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::AnalogRead, "AnalogRead");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::Pwm, "Pwm");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::Debounce, "Debounce");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::Pulse, "Pulse");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::Blink, "Blink");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::MeasurePulse, "MeasurePulse");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::CountEdges, "CountEdges");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::ReadCount, "ReadCount");
+                        ui.selectable_value(
+                            &mut self.selected_action_type,
+                            ActionType::MeasureFrequency,
+                            "MeasureFrequency",
+                        );
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::Capture, "Capture");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::ReadCapture, "ReadCapture");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::Stream, "Stream");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::LoadPattern, "LoadPattern");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::Play, "Play");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::Heartbeat, "Heartbeat");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::SafeState, "SafeState");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::PowerOn, "PowerOn");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::ReadPowerOn, "ReadPowerOn");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::FactoryReset, "FactoryReset");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::Label, "Label");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::Unlabel, "Unlabel");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::Relabel, "Relabel");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::Identify, "Identify");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::SetName, "SetName");
+                        ui.selectable_value(&mut self.selected_action_type, ActionType::SetId, "SetId");
                     });
 
                 match self.selected_action_type {
@@ -196,70 +694,284 @@ impl eframe::App for TemplateApp {
                         single_character_text(ui, &mut self.pin_label);
                         ui.checkbox(&mut self.pin_high, "Set pin high");
                     }
-                    ActionType::Input => {
+                    ActionType::Input
+                    | ActionType::AnalogRead
+                    | ActionType::ReadPowerOn
+                    | ActionType::Unlabel
+                    | ActionType::Query => {
+                        single_character_text(ui, &mut self.pin_label);
+                    }
+                    ActionType::SetMode => {
+                        single_character_text(ui, &mut self.pin_label);
+                        pin_mode_selector(ui, "selected_pin_mode", &mut self.pin_mode);
+                    }
+                    ActionType::Pwm => {
+                        single_character_text(ui, &mut self.pin_label);
+                        ui.add(Slider::new(&mut self.pwm_duty, 0..=u8::MAX).text("Duty cycle"));
+                    }
+                    ActionType::Debounce => {
+                        single_character_text(ui, &mut self.pin_label);
+                        ui.add(DragValue::new(&mut self.debounce_millis).suffix("ms"));
+                    }
+                    ActionType::Pulse => {
+                        single_character_text(ui, &mut self.pin_label);
+                        ui.checkbox(&mut self.pin_high, "High pulse");
+                        ui.add(
+                            DragValue::new(&mut self.pulse_width_us)
+                                .clamp_range(0..=MAX_BLOCKING_US)
+                                .suffix("µs"),
+                        );
+                    }
+                    ActionType::Blink => {
+                        single_character_text(ui, &mut self.pin_label);
+                        ui.add(DragValue::new(&mut self.blink_period_ms).suffix("ms"));
+                        ui.add(Slider::new(&mut self.blink_duty, 0..=100).suffix("%"));
+                    }
+                    ActionType::MeasurePulse => {
                         single_character_text(ui, &mut self.pin_label);
+                        ui.checkbox(&mut self.pin_high, "High pulse");
+                        ui.label("Timeout");
+                        ui.add(
+                            DragValue::new(&mut self.measure_timeout_us)
+                                .clamp_range(0..=MAX_BLOCKING_US)
+                                .suffix("µs"),
+                        );
+                    }
+                    ActionType::CountEdges => {
+                        single_character_text(ui, &mut self.pin_label);
+                        edge_selector(ui, "selected_count_edge", &mut self.count_edge);
+                    }
+                    ActionType::ReadCount => {
+                        single_character_text(ui, &mut self.pin_label);
+                        ui.checkbox(&mut self.reset_count, "Reset");
+                    }
+                    ActionType::MeasureFrequency => {
+                        single_character_text(ui, &mut self.pin_label);
+                        ui.label("Gate time");
+                        ui.add(
+                            DragValue::new(&mut self.gate_ms)
+                                .clamp_range(1..=MAX_GATE_MS)
+                                .suffix("ms"),
+                        );
+                    }
+                    ActionType::Capture => {
+                        single_character_text(ui, &mut self.pin_label);
+                        ui.label("Edges");
+                        ui.add(DragValue::new(&mut self.capture_limit).clamp_range(0..=MAX_CAPTURE_EDGES));
+                    }
+                    ActionType::ReadCapture => {
+                        single_character_text(ui, &mut self.pin_label);
+                    }
+                    ActionType::Stream => {
+                        ui.label(format!("Period, 0 to stop, at least {}µs", self.min_stream_period_us()));
+                        ui.add(
+                            DragValue::new(&mut self.stream_period_us)
+                                .clamp_range(0..=MAX_SAMPLE_PERIOD_US)
+                                .suffix("µs"),
+                        );
+                    }
+                    ActionType::LoadPattern => {
+                        ui.label("Pins");
+                        ui.add(TextEdit::singleline(&mut self.pattern_pins).desired_width(40.0));
+                        ui.label("Steps");
+                        ui.add(TextEdit::singleline(&mut self.pattern_steps).hint_text("10 01 11"));
+                        ui.add(
+                            DragValue::new(&mut self.pattern_tick_us)
+                                .clamp_range(MIN_PATTERN_TICK_US..=MAX_PATTERN_TICK_US)
+                                .suffix("µs"),
+                        );
+                    }
+                    ActionType::Heartbeat => {
+                        ui.label("Timeout, 0 to disable");
+                        ui.add(DragValue::new(&mut self.heartbeat_ms).suffix("ms"));
+                    }
+                    ActionType::SafeState => {
+                        single_character_text(ui, &mut self.pin_label);
+                        let text = |state: Option<PinState>| match state {
+                            Some(state) => format!("Drive {:?}", state),
+                            None => "Input".to_owned(),
+                        };
+                        ComboBox::from_id_source("selected_safe_state")
+                            .selected_text(text(self.safe_state))
+                            .show_ui(ui, |ui| {
+                                for state in [None, Some(PinState::Low), Some(PinState::High)] {
+                                    ui.selectable_value(&mut self.safe_state, state, text(state));
+                                }
+                            });
+                    }
+                    ActionType::PowerOn => {
+                        single_character_text(ui, &mut self.pin_label);
+                        pin_mode_selector(ui, "selected_power_on_mode", &mut self.pin_mode);
+                        ui.checkbox(&mut self.pin_high, "High");
+                        ui.label("Applied after the next reset");
+                    }
+                    ActionType::Label => {
+                        single_character_text(ui, &mut self.pin_label);
+                        ui.label("Pin name");
+                        // Pin names are at most three characters long
+                        self.pin_name.retain(|c| c.is_ascii_alphanumeric());
+                        self.pin_name.truncate(3);
+                        ui.add(
+                            TextEdit::singleline(&mut self.pin_name)
+                                .hint_text("d13")
+                                .desired_width(30.0),
+                        );
+                    }
+                    ActionType::Relabel => {
+                        single_character_text(ui, &mut self.pin_label);
+                        ui.label("New label");
+                        single_character_text(ui, &mut self.new_pin_label);
+                    }
+                    ActionType::Play => {
+                        ComboBox::from_id_source("selected_playback")
+                            .selected_text(format!("{:?}", self.playback))
+                            .show_ui(ui, |ui| {
+                                for playback in [Playback::Stop, Playback::Once, Playback::Loop] {
+                                    ui.selectable_value(&mut self.playback, playback, format!("{:?}", playback));
+                                }
+                            });
+                    }
+                    ActionType::List | ActionType::ListPins | ActionType::Hello | ActionType::Identify => (),
+                    ActionType::SetName => {
+                        while self.device_name.len() > MAX_DEVICE_NAME_LEN {
+                            self.device_name.pop();
+                        }
+                        ui.add(TextEdit::singleline(&mut self.device_name).hint_text("Device name"));
+                        if DeviceName::new(&self.device_name).is_none() {
+                            ui.label("Can't contain zero bytes");
+                        }
+                    }
+                    ActionType::SetId => {
+                        ui.add(TextEdit::singleline(&mut self.device_id).hint_text("Device ID"));
+                        if DeviceId::parse(&self.device_id).is_none() {
+                            ui.label("Needs 32 hex digits, like the IDs Identify reports");
+                        }
+                    }
+                    ActionType::FactoryReset => {
+                        ui.label("Forgets every stored setting after the next reset");
                     }
-                    ActionType::List => (),
                 };
             });
 
             let pin_label = self.pin_label.chars().next().unwrap_or(DEFAULT_PIN_LABEL);
 
-            let action = match self.selected_action_type {
-                ActionType::Output => {
-                    Action::Output(pin_label, if self.pin_high { PinState::High } else { PinState::Low })
-                }
-                ActionType::Input => Action::Input(pin_label),
-                ActionType::List => Action::List,
-            };
+            let pin_state = if self.pin_high { PinState::High } else { PinState::Low };
+            let action = self.action(pin_label, pin_state);
 
-            let serialized_action = postcard::to_stdvec(&action).expect("Failed to serialize action!");
-            let deserialized_action: Action = postcard::from_bytes(&serialized_action).expect("Failed to deserialize!");
-            ui.horizontal(|ui| {
-                ui.vertical(|ui| {
-                    ui.heading("Action object");
-                    ui.label(format!("{:?}", action));
-                });
-                ui.vertical(|ui| {
-                    ui.heading("in hex:");
-                    ui.label(format!("{:02x?}", serialized_action));
-                });
-                ui.vertical(|ui| {
-                    ui.heading("deserialized again:");
-                    ui.label(format!("{:?}", deserialized_action))
+            if let Some(action) = action {
+                let serialized_action = postcard::to_stdvec(&action).expect("Failed to serialize action!");
+                let deserialized_action: Action =
+                    postcard::from_bytes(&serialized_action).expect("Failed to deserialize!");
+                ui.horizontal(|ui| {
+                    ui.vertical(|ui| {
+                        ui.heading("Action object");
+                        ui.label(format!("{:?}", action));
+                    });
+                    ui.vertical(|ui| {
+                        ui.heading("in hex:");
+                        ui.label(format!("{:02x?}", serialized_action));
+                    });
+                    ui.vertical(|ui| {
+                        ui.heading("deserialized again:");
+                        ui.label(format!("{:?}", deserialized_action))
+                    });
                 });
-            });
+            }
 
             let mut disconnect = false;
-            if self.serial_port.lock().is_some() {
+            if self.client.lock().is_some() {
                 ui.heading("Serial connection");
                 ui.horizontal(|ui| {
                     ui.label(format!(
                         "Connected to {}",
-                        self.serial_port.lock().as_ref().unwrap().name().unwrap_or_default()
+                        self.client.lock().as_ref().unwrap().port().name().unwrap_or_default()
                     ));
                     if ui.button("Disconnect").clicked() {
                         disconnect = true;
                     }
                 });
 
-                ui.horizontal(|ui| {
-                    ui.vertical(|ui| {
-                        if ui.button("Send action").clicked() {
-                            self.send_action(action)
-                        }
-
+                match self.device_info() {
+                    None => {
+                        self.say_hello();
+                        ui.label("Waiting for the firmware to identify itself...");
                         self.serial_output_text(ui, 30);
-                    });
-
-                    self.build_pin_list(ui)
-                });
+                        ctx.request_repaint();
+                    }
+                    Some(device_info) if !device_info.is_compatible() => {
+                        ui.colored_label(
+                            Color32::RED,
+                            format!(
+                                "The firmware speaks protocol version {}, but this version of serial-gui only speaks \
+                                 version {}. Please update one of them.",
+                                device_info.protocol_version, PROTOCOL_VERSION
+                            ),
+                        );
+                    }
+                    Some(device_info) => {
+                        let version = device_info.firmware_version;
+                        ui.label(format!(
+                            "{:?} with {} pins, firmware version {}.{}.{}",
+                            device_info.board, device_info.pin_count, version.major, version.minor, version.patch
+                        ));
+                        if self.supports(Capabilities::IDENTITY) && !self.identify_sent {
+                            self.send_action(Action::Identify);
+                            self.identify_sent = true;
+                        }
+                        if let Some(identity) = self.client.lock().as_ref().and_then(|client| client.identity()) {
+                            ui.label(format!("Name: {:?}, ID: {}", identity.name, identity.id));
+                        }
+                        if let Some(tripped_ms) =
+                            self.client.lock().as_ref().and_then(|client| client.failsafe_tripped())
+                        {
+                            ui.colored_label(
+                                Color32::RED,
+                                format!(
+                                    "No heartbeat arrived in time, so the outputs were put into their safe states. \
+                                     That was {}ms before the connection came back",
+                                    tripped_ms
+                                ),
+                            );
+                        }
+                        self.keep_alive(ctx);
+
+                        ui.horizontal(|ui| {
+                            ui.vertical(|ui| {
+                                let send = ui.add_enabled(action.is_some(), Button::new("Send action"));
+                                if let (true, Some(action)) = (send.clicked(), action) {
+                                    match (action, self.pattern()) {
+                                        (Action::LoadPattern(mask, tick_us), Some((_, steps))) => {
+                                            self.load_pattern(mask, tick_us, &steps)
+                                        }
+                                        _ => self.send_action(action),
+                                    }
+                                }
+
+                                self.serial_output_text(ui, 30);
+                            });
+
+                            if self.selected_action_type == ActionType::ReadCapture {
+                                if let Some(text) = self.capture_text(pin_label) {
+                                    ui.label(text);
+                                }
+                            }
+                            self.build_pin_list(ui)
+                        });
+                    }
+                }
             } else {
                 ui.heading("Serial ports");
                 let ports = serialport::available_ports().expect("No serial ports found!");
+                if ui.button("Identify boards").clicked() {
+                    let usb_ports = ports
+                        .iter()
+                        .filter(|port| matches!(port.port_type, serialport::SerialPortType::UsbPort(_)));
+                    self.identify_boards(usb_ports.cloned().collect(), ctx);
+                }
                 for port in ports {
+                    let identity = self.identities.lock().get(&port.port_name).copied();
                     ui.horizontal(|ui| {
-                        ui.label(format_port(&port));
+                        ui.label(format_port(&port, identity.as_ref()));
                         if let serialport::SerialPortType::UsbPort(_) = port.port_type.clone() {
                             if ui.button("Connect").clicked() {
                                 self.connect(port)
@@ -271,8 +983,15 @@ impl eframe::App for TemplateApp {
             if disconnect {
                 self.serial_responses = Default::default();
                 self.pin_map = Default::default();
-                self.bytes_read = 0;
-                self.serial_port = Default::default();
+                self.list_sent = false;
+                self.pin_levels = Default::default();
+                self.analog_readings = Default::default();
+                self.pwm_duties = Default::default();
+                self.pwm_prescalers = Default::default();
+                self.hello_sent = None;
+                self.heartbeat_sent = None;
+                self.identify_sent = false;
+                self.client = Default::default();
             }
         });
     }